@@ -3,7 +3,7 @@ mod utils;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgExecutor};
 
 pub use middlewares::*;
 pub use utils::*;
@@ -39,6 +39,23 @@ pub struct ChatUser {
     pub id: i64,
     pub fullname: String,
     pub email: String,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub presence: Presence,
+}
+
+/// Presence of a user, derived from the active event connections of the user.
+#[derive(
+    Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, Eq, sqlx::Type,
+)]
+#[sqlx(type_name = "presence_status", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum Presence {
+    Online,
+    /// All connections are gone for a moment, e.g. the page is reloading
+    Away,
+    #[default]
+    Offline,
 }
 
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
@@ -55,6 +72,15 @@ pub enum ChatType {
     PublicChannel,
 }
 
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "chat_role", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum ChatRole {
+    Owner,
+    Admin,
+    Member,
+}
+
 #[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all(serialize = "camelCase"))]
 pub struct Chat {
@@ -64,6 +90,16 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    #[sqlx(default)]
+    #[serde(alias = "ownerId")]
+    pub owner_id: Option<i64>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub admins: Vec<i64>,
+    /// Messages from others the current user hasn't read yet
+    #[sqlx(default)]
+    #[serde(default, alias = "unreadCount")]
+    pub unread_count: i64,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
 }
@@ -80,6 +116,109 @@ pub struct Message {
     pub files: Vec<String>,
     #[serde(alias = "createdAt")]
     pub created_at: DateTime<Utc>,
+    #[serde(alias = "updatedAt")]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(alias = "deletedAt")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[serde(alias = "replyTo")]
+    pub reply_to: Option<i64>,
+    #[serde(alias = "threadRootId")]
+    pub thread_root_id: Option<i64>,
+    #[sqlx(default)]
+    #[serde(default, alias = "replyCount")]
+    pub reply_count: i64,
+    #[sqlx(default)]
+    #[serde(alias = "lastReplyAt")]
+    pub last_reply_at: Option<DateTime<Utc>>,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+}
+
+/// Reactions with the same emoji on a message.
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i64,
+    #[serde(alias = "userIds")]
+    pub user_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct MessageReaction {
+    #[serde(alias = "messageId")]
+    pub message_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    pub emoji: String,
+    #[serde(alias = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ReadReceipt {
+    #[serde(alias = "chatId")]
+    pub chat_id: i64,
+    #[serde(alias = "userId")]
+    pub user_id: i64,
+    #[serde(alias = "lastReadId")]
+    pub last_read_id: i64,
+    #[serde(alias = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Chat {
+    /// Tell the other members that the user is typing in the chat. The signal is ephemeral,
+    /// it goes through the notification channel only and nothing is stored. The membership is
+    /// checked by the caller.
+    pub async fn notify_typing<'e>(
+        executor: impl PgExecutor<'e>,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            SELECT pg_notify('chat_typing', json_build_object('event_id', nextval('notify_event_id_seq'), 'chat_id', $1, 'user_id', $2)::text)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl ReadReceipt {
+    /// Move the read receipt of the user forward to the message, the trigger notifies the chat
+    /// members. Returns `None` if the user read further already, the receipt never moves
+    /// backwards.
+    pub async fn upsert<'e>(
+        executor: impl PgExecutor<'e>,
+        chat_id: i64,
+        user_id: i64,
+        last_read_id: i64,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            INSERT INTO read_receipts(chat_id, user_id, last_read_id)
+            VALUES($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET last_read_id = EXCLUDED.last_read_id, updated_at = CURRENT_TIMESTAMP
+            WHERE read_receipts.last_read_id < EXCLUDED.last_read_id
+            RETURNING chat_id, user_id, last_read_id, updated_at
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(last_read_id)
+        .fetch_optional(executor)
+        .await
+    }
 }
 
 #[cfg(test)]
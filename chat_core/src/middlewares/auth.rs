@@ -12,6 +12,7 @@ use serde::Deserialize;
 use tracing::warn;
 
 use super::TokenVerify;
+use crate::Scope;
 
 #[derive(Debug, Deserialize)]
 struct Params {
@@ -44,14 +45,31 @@ where
             }
         };
 
-    let req = match state.verify(&token) {
+    let claims = match state.verify(&token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            let msg = format!("verify token failed: {:?}", e);
+            warn!(msg);
+            return (StatusCode::FORBIDDEN, msg).into_response();
+        }
+    };
+
+    if !claims.has_scope(Scope::Chat) {
+        let msg = "token without the chat scope".to_string();
+        warn!(msg);
+        return (StatusCode::FORBIDDEN, msg).into_response();
+    }
+
+    // handlers get the user, and the claims e.g. to sign the session out
+    let req = match state.verify_session(&claims).await {
         Ok(user) => {
             let mut req = Request::from_parts(parts, body);
             req.extensions_mut().insert(user);
+            req.extensions_mut().insert(claims);
             req
         }
         Err(e) => {
-            let msg = format!("verify token failed: {:?}", e);
+            let msg = format!("verify session failed: {:?}", e);
             warn!(msg);
             return (StatusCode::FORBIDDEN, msg).into_response();
         }
@@ -69,7 +87,7 @@ mod tests {
     use tower::ServiceExt;
 
     use crate::{
-        utils::{DecodingKey, EncodingKey},
+        utils::{DecodingKey, EncodingKey, TokenClaims},
         User,
     };
 
@@ -92,7 +110,12 @@ mod tests {
         let state = AppState(Arc::new(AppStateInner { ek, dk }));
 
         let user = User::new(1, "hedon", "hedon@example.com");
-        let token = state.ek.sign(user)?;
+        let token = state.ek.sign(TokenClaims::new(&user, 1))?;
+        let revoked_token = state.ek.sign(TokenClaims::new(&user, 2))?;
+        let unscoped_token = state.ek.sign(TokenClaims {
+            scopes: vec![],
+            ..TokenClaims::new(&user, 1)
+        })?;
 
         let app = Router::new()
             .route("/", get(handler))
@@ -138,6 +161,22 @@ mod tests {
         let rsp = app.clone().oneshot(req).await?;
         assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
 
+        // valid token of a session revoked by the state
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {}", revoked_token))
+            .body(Body::empty())?;
+        let rsp = app.clone().oneshot(req).await?;
+        assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+
+        // valid token without the chat scope
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {}", unscoped_token))
+            .body(Body::empty())?;
+        let rsp = app.oneshot(req).await?;
+        assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+
         Ok(())
     }
 
@@ -147,9 +186,14 @@ mod tests {
 
     impl TokenVerify for AppState {
         type Error = anyhow::Error;
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
             self.0.dk.verify(token)
         }
+
+        async fn verify_session(&self, claims: &TokenClaims) -> Result<User, Self::Error> {
+            anyhow::ensure!(claims.sid != 2, "session 2 is revoked");
+            Ok(User::new(claims.uid, "hedon", "hedon@example.com"))
+        }
     }
     impl Deref for AppState {
         type Target = AppStateInner;
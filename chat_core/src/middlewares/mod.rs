@@ -3,6 +3,7 @@ mod request_id;
 mod server_time;
 
 use core::fmt;
+use std::future::Future;
 
 use axum::Router;
 use request_id::RequestIDLayer;
@@ -14,7 +15,7 @@ use tower_http::{
 };
 use tracing::Level;
 
-use crate::User;
+use crate::{TokenClaims, User};
 
 pub use auth::verify_token;
 
@@ -23,7 +24,15 @@ const REQUEST_TIME_HEADER: &str = "x-server-time";
 
 pub trait TokenVerify {
     type Error: fmt::Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error>;
+
+    /// Load the user of the claims of a valid token for the handlers. Fails if the session
+    /// of the token is gone or its user can't get in anymore, e.g. it has been revoked or the
+    /// user deactivated since the token was signed.
+    fn verify_session(
+        &self,
+        claims: &TokenClaims,
+    ) -> impl Future<Output = Result<User, Self::Error>> + Send;
 }
 
 pub trait SetRequestID {
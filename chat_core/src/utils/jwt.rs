@@ -1,25 +1,73 @@
+use std::collections::HashMap;
+
 use crate::User;
 
 use jwt_simple::prelude::*;
 
-#[allow(unused)]
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7;
+/// Access tokens are short-lived, clients get new ones with the refresh token of the session.
+pub const ACCESS_TOKEN_DURATION: u64 = 60 * 15;
 const JWT_ISS: &str = "chat_server";
 const JWT_AUD: &str = "chat_web";
 
+/// What an access token grants access to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// The chat api and the events of the user
+    Chat,
+    /// One-time token mailed to verify the email of the user
+    VerifyEmail,
+    /// One-time token mailed to reset the password of the user
+    ResetPassword,
+    /// Signin waiting for the second factor of the user
+    TwoFactor,
+    /// Scopes of newer servers are ignored
+    #[serde(other)]
+    Unknown,
+}
+
+/// Claims of an access token besides the registered ones. Only ids, the user is loaded by
+/// the servers so tokens neither get stale nor carry personal data.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenClaims {
+    pub uid: i64,
+    /// Workspace the token is scoped to
+    pub ws_id: i64,
+    /// Session the token was issued for, to check it isn't revoked. Id of the one-time token
+    /// for the tokens mailed to the user.
+    pub sid: i64,
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+}
+
+/// The signing key, tokens are stamped with its key id in the `kid` header.
 #[allow(unused)]
 pub struct EncodingKey(Ed25519KeyPair);
 
-pub struct DecodingKey(Ed25519PublicKey);
+/// Keyset of the public keys accepted, by key id. Holding the previous key besides the current
+/// one lets its tokens expire instead of logging everyone out when the signing key rotates.
+pub struct DecodingKey(HashMap<String, Ed25519PublicKey>);
 
 #[allow(unused)]
 impl EncodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519KeyPair::from_pem(pem)?))
+        let key = Ed25519KeyPair::from_pem(pem)?;
+        let kid = key.public_key().sha256_thumbprint();
+        Ok(Self(key.with_key_id(&kid)))
     }
 
-    pub fn sign(&self, user: impl Into<User>) -> Result<String, jwt_simple::Error> {
-        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(JWT_DURATION));
+    /// Key id derived from the public key, the same on all servers loading the key.
+    pub fn kid(&self) -> &str {
+        self.0.key_id().as_deref().unwrap_or_default()
+    }
+
+    pub fn sign(&self, claims: TokenClaims) -> Result<String, jwt_simple::Error> {
+        self.sign_for(claims, ACCESS_TOKEN_DURATION)
+    }
+
+    /// Sign a token valid for the seconds, e.g. a one-time token mailed to the user.
+    pub fn sign_for(&self, claims: TokenClaims, secs: u64) -> Result<String, jwt_simple::Error> {
+        let claims = Claims::with_custom_claims(claims, Duration::from_secs(secs));
         let claims = claims.with_issuer(JWT_ISS).with_audience(JWT_AUD);
         self.0.sign(claims)
     }
@@ -28,21 +76,61 @@ impl EncodingKey {
 #[allow(unused)]
 impl DecodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519PublicKey::from_pem(pem)?))
+        Self::load_all([pem])
     }
 
-    pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
+    /// Load a keyset, e.g. the current public key and the previous ones.
+    pub fn load_all<'a>(
+        pems: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, jwt_simple::Error> {
+        let mut keys = HashMap::new();
+        for pem in pems {
+            let key = Ed25519PublicKey::from_pem(pem)?;
+            let kid = key.sha256_thumbprint();
+            keys.insert(kid.clone(), key.with_key_id(&kid));
+        }
+        if keys.is_empty() {
+            return Err(jwt_simple::Error::msg("no public key to verify tokens"));
+        }
+        Ok(Self(keys))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, jwt_simple::Error> {
         let opts = VerificationOptions {
             allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
             allowed_audiences: Some(HashSet::from_strings(&[JWT_AUD])),
             ..VerificationOptions::default()
         };
 
-        let claims = self.0.verify_token::<User>(token, Some(opts))?;
+        let metadata = Token::decode_metadata(token)?;
+        let kid = metadata
+            .key_id()
+            .ok_or_else(|| jwt_simple::Error::msg("token without key id"))?;
+        let key = self
+            .0
+            .get(kid)
+            .ok_or_else(|| jwt_simple::Error::msg(format!("unknown key id {kid}")))?;
+        let claims = key.verify_token::<TokenClaims>(token, Some(opts))?;
         Ok(claims.custom)
     }
 }
 
+impl TokenClaims {
+    /// Claims of a token to the chat for the session of the user, in its current workspace.
+    pub fn new(user: &User, sid: i64) -> Self {
+        Self {
+            uid: user.id,
+            ws_id: user.ws_id,
+            sid,
+            scopes: vec![Scope::Chat],
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -61,10 +149,45 @@ mod tests {
 
         let user = User::new(1, "hedon", "hedon@example.com");
 
-        let token = ek.sign(user.clone())?;
-        let user_from_token = dk.verify(&token)?;
+        let claims = TokenClaims::new(&user, 42);
+        let token = ek.sign(claims.clone())?;
+        assert_eq!(dk.verify(&token)?, claims);
+
+        // only ids in the token
+        let metadata = Token::decode_metadata(&token)?;
+        let payload = token.split('.').nth(1).unwrap();
+        let payload = Base64UrlSafeNoPadding::decode_to_vec(payload, None)?;
+        let payload = String::from_utf8(payload)?;
+        assert!(metadata.key_id().is_some());
+        assert!(!payload.contains("hedon"));
+        assert!(payload.contains(r#""scopes":["chat"]"#));
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_key_rotation_should_work() -> anyhow::Result<()> {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+        let previous = EncodingKey::load(encoding_pem)?;
+        let current = EncodingKey::load(&Ed25519KeyPair::generate().to_pem())?;
+        assert_ne!(previous.kid(), current.kid());
+
+        let user = User::new(1, "hedon", "hedon@example.com");
+        let old_token = previous.sign(TokenClaims::new(&user, 1))?;
+        let new_token = current.sign(TokenClaims::new(&user, 2))?;
+        let metadata = Token::decode_metadata(&new_token)?;
+        assert_eq!(metadata.key_id(), Some(current.kid()));
+
+        // the keyset accepts tokens of both keys
+        let current_pem = current.0.public_key().to_pem();
+        let dk = DecodingKey::load_all([current_pem.as_str(), decoding_pem])?;
+        assert_eq!(dk.verify(&old_token)?.sid, 1);
+        assert_eq!(dk.verify(&new_token)?.sid, 2);
 
-        assert_eq!(user, user_from_token);
+        // the previous key is dropped
+        let dk = DecodingKey::load(&current_pem)?;
+        assert!(dk.verify(&old_token).is_err());
+        assert_eq!(dk.verify(&new_token)?.uid, user.id);
         Ok(())
     }
 }
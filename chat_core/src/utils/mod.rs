@@ -1,3 +1,5 @@
 mod jwt;
+mod session;
 
-pub use jwt::{DecodingKey, EncodingKey};
+pub use jwt::{DecodingKey, EncodingKey, Scope, TokenClaims, ACCESS_TOKEN_DURATION};
+pub use session::SessionCache;
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sqlx::PgPool;
+
+use super::TokenClaims;
+use crate::User;
+
+const SESSION_CACHE_TTL: Duration = Duration::from_secs(30);
+const SESSION_CACHE_CAPACITY: usize = 10_000;
+
+/// (session id, workspace id) -> (loaded at, user if the session is alive)
+type Entries = HashMap<(i64, i64), (Instant, Option<User>)>;
+
+/// Remembers for a short while the user of the session of an access token, so that verifying
+/// a token doesn't hit the database on every request. A revoked session is rejected, and a
+/// renamed user is seen, by the other servers after the ttl at the latest.
+pub struct SessionCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The user of the session scoped to the workspace of the token, if the session is neither
+    /// revoked nor expired and the user is still an active member of the workspace.
+    pub async fn load(
+        &self,
+        claims: &TokenClaims,
+        pool: &PgPool,
+    ) -> Result<Option<User>, sqlx::Error> {
+        if let Some(user) = self.get(claims) {
+            return Ok(user);
+        }
+
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            SELECT u.id, m.ws_id, w.name AS ws_name, u.fullname, u.email, u.created_at
+            FROM sessions s
+            JOIN users u ON u.id = s.user_id
+            JOIN workspace_members m ON m.user_id = s.user_id
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE s.id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL
+                AND s.expires_at > CURRENT_TIMESTAMP
+                AND m.ws_id = $3 AND m.deactivated_at IS NULL
+            "#,
+        )
+        .bind(claims.sid)
+        .bind(claims.uid)
+        .bind(claims.ws_id)
+        .fetch_optional(pool)
+        .await?;
+        self.insert(claims, user.clone());
+
+        Ok(user)
+    }
+
+    fn get(&self, claims: &TokenClaims) -> Option<Option<User>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(claims.sid, claims.ws_id))
+            .filter(|(loaded_at, _)| loaded_at.elapsed() < self.ttl)
+            .map(|(_, user)| user.clone())
+    }
+
+    fn insert(&self, claims: &TokenClaims, user: Option<User>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, (loaded_at, _)| loaded_at.elapsed() < self.ttl);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+        }
+        entries.insert((claims.sid, claims.ws_id), (Instant::now(), user));
+    }
+
+    /// Forget the session in all workspaces, e.g. after it is revoked on this server.
+    pub fn remove(&self, sid: i64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(id, _), _| *id != sid);
+    }
+}
+
+impl Default for SessionCache {
+    fn default() -> Self {
+        Self::new(SESSION_CACHE_TTL, SESSION_CACHE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_cache_should_work() {
+        let cache = SessionCache::new(Duration::from_millis(50), 2);
+        let user = User::new(1, "hedon", "hedon@example.com");
+        let claims = |sid, ws_id| TokenClaims {
+            ws_id,
+            ..TokenClaims::new(&user, sid)
+        };
+
+        cache.insert(&claims(1, 1), Some(user.clone()));
+        cache.insert(&claims(1, 2), None);
+        assert_eq!(cache.get(&claims(1, 1)), Some(Some(user.clone())));
+        assert_eq!(cache.get(&claims(1, 2)), Some(None));
+        assert_eq!(cache.get(&claims(2, 1)), None);
+
+        // full of live entries, start over
+        cache.insert(&claims(2, 1), Some(user.clone()));
+        assert_eq!(cache.get(&claims(1, 1)), None);
+        assert!(cache.get(&claims(2, 1)).is_some());
+
+        cache.remove(2);
+        assert_eq!(cache.get(&claims(2, 1)), None);
+
+        cache.insert(&claims(3, 1), Some(user.clone()));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get(&claims(3, 1)), None);
+    }
+}
@@ -15,6 +15,12 @@ VALUES
 (1, 'joe@acme.com', 'Joe', '$argon2id$v=19$m=19456,t=2,p=1$oAz92hafJDxT/KZeFUP1Rg$Mj2NpMdquq74Z/kOd3rqmep98XQmJwkgDSbIxU7qegc'),
 (1, 'jim@acme.com', 'Jim', '$argon2id$v=19$m=19456,t=2,p=1$oAz92hafJDxT/KZeFUP1Rg$Mj2NpMdquq74Z/kOd3rqmep98XQmJwkgDSbIxU7qegc');
 
+INSERT INTO workspace_members(ws_id, user_id)
+SELECT ws_id, id FROM users WHERE id > 0;
+
+-- hedon owns acme
+UPDATE workspaces SET owner_id = 1 WHERE id = 1;
+
 -- insert 4 chats
 -- insert public/private channel
 INSERT INTO chats (ws_id, name, type, members)
@@ -37,4 +43,13 @@ INSERT INTO messages(chat_id, sender_id, content)
 (1, 2, 'Hi, there!'),
 (1, 3, 'How are you?'),
 (1, 1, 'Hello, world!'),
-(1, 1, 'Hello, world!');
\ No newline at end of file
+(1, 1, 'Hello, world!');
+
+-- user 1 owns all chats, user 2 is admin of chat 1
+INSERT INTO chat_roles(chat_id, user_id, role)
+VALUES
+(1, 1, 'owner'),
+(1, 2, 'admin'),
+(2, 1, 'owner'),
+(3, 1, 'owner'),
+(4, 1, 'owner');
@@ -13,15 +13,57 @@ pub enum AppError {
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("update message error: {0}")]
+    UpdateMessageError(String),
+
     #[error("create chat error: {0}")]
     CreateChatError(String),
 
+    #[error("update chat error: {0}")]
+    UpdateChatError(String),
+
     #[error("{0}")]
     ChatFileError(String),
 
+    #[error("reaction error: {0}")]
+    ReactionError(String),
+
+    #[error("search error: {0}")]
+    SearchError(String),
+
+    #[error("list messages error: {0}")]
+    ListMessagesError(String),
+
+    #[error("invite error: {0}")]
+    InviteError(String),
+
+    #[error("workspace error: {0}")]
+    WorkspaceError(String),
+
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("invalid session: {0}")]
+    InvalidSession(String),
+
+    #[error("oidc error: {0}")]
+    OidcError(String),
+
+    #[error("email token error: {0}")]
+    EmailTokenError(String),
+
+    #[error("email not verified: {0}")]
+    EmailNotVerified(String),
+
+    #[error("two-factor error: {0}")]
+    TwoFactorError(String),
+
+    #[error("two-factor required: {0}")]
+    TwoFactorRequired(String),
+
     #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
 
@@ -58,9 +100,23 @@ impl IntoResponse for AppError {
             Self::AnyhowError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateChatError(_) => StatusCode::BAD_REQUEST,
             Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateMessageError(_) => StatusCode::BAD_REQUEST,
             Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
+            Self::ReactionError(_) => StatusCode::BAD_REQUEST,
+            Self::SearchError(_) => StatusCode::BAD_REQUEST,
+            Self::ListMessagesError(_) => StatusCode::BAD_REQUEST,
+            Self::InviteError(_) => StatusCode::BAD_REQUEST,
+            Self::WorkspaceError(_) => StatusCode::BAD_REQUEST,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::InvalidSession(_) => StatusCode::UNAUTHORIZED,
+            Self::OidcError(_) => StatusCode::UNAUTHORIZED,
+            Self::EmailTokenError(_) => StatusCode::BAD_REQUEST,
+            Self::EmailNotVerified(_) => StatusCode::FORBIDDEN,
+            Self::TwoFactorError(_) => StatusCode::UNAUTHORIZED,
+            Self::TwoFactorRequired(_) => StatusCode::FORBIDDEN,
             Self::IOError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -1,23 +1,50 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use chat_core::{TokenClaims, User, ACCESS_TOKEN_DURATION};
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use utoipa::ToSchema;
 
 use crate::{
     error::ErrorOutput,
-    models::{CreateUser, SigninUser},
+    models::{
+        CreateUser, OidcCallback, RefreshSession, RequestEmail, ResetPassword, SigninUser,
+        TotpCode, TwoFactorSignin, VerifyEmail,
+    },
     AppError, AppState,
 };
 
+/// Keeps the state of an OIDC login in the browser that started it.
+const OIDC_STATE_COOKIE: &str = "oidc_state";
+const OIDC_COOKIE_PATH: &str = "/api/oidc";
+
 #[derive(Debug, ToSchema, Serialize, Deserialize)]
 pub struct AuthOutput {
+    /// Short-lived access token
     token: String,
+    /// Seconds until the access token expires
+    expires_in: u64,
+    /// Token to get the next access token with, it can be used only once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    /// Recovery codes of the authenticator enrolled while signing in, shown only once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
 }
 
 #[utoipa::path(
     post,
     path = "/api/signup",
     responses(
-        (status = 200, description = "User created", body = AuthOutput),
+        (status = 201, description = "User created", body = AuthOutput),
+        (status = 202, description = "Two-factor authentication required", body = TwoFactorChallenge),
+        (status = 403, description = "Invite required or invalid, or email not verified", body = ErrorOutput),
+        (status = 409, description = "Email already exists", body = ErrorOutput),
     )
 )]
 /// POST /api/signup
@@ -25,16 +52,22 @@ pub struct AuthOutput {
 /// Create a new user in the chat system with email, password workspace and full name.
 ///
 /// - If the email already exists, it will return 409.
+/// - If the workspace exists and the invite to it is missing or invalid, it will return 403.
 /// - Otherwise, it will return 201 with a token.
 /// - If the workspace doesn't exist, it will create one.
+///
+/// A verification mail is sent to the email. If the workspace requires a verified email, the
+/// user is created but it will return 403 until the email is verified.
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
-    let body = Json(AuthOutput { token });
-    Ok((StatusCode::CREATED, body))
+    // the user can ask for another mail
+    if let Err(e) = state.send_verification_email(&user).await {
+        warn!("send verification mail to user {} failed: {e}", user.id);
+    }
+    sign_in(&state, user, StatusCode::CREATED).await
 }
 
 #[utoipa::path(
@@ -42,9 +75,13 @@ pub(crate) async fn signup_handler(
     path = "/api/signin",
     responses(
         (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 202, description = "Two-factor authentication required", body = TwoFactorChallenge),
     )
 )]
 /// Sign in a user with email and password.
+///
+/// If the user enabled two-factor authentication, or the workspace requires it, it will return
+/// 202 with a challenge to finish the signin with at `/api/signin/2fa`.
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
     Json(input): Json<SigninUser>,
@@ -52,10 +89,7 @@ pub(crate) async fn signin_handler(
     let user = state.verify_user(&input).await?;
 
     match user {
-        Some(user) => {
-            let token = state.ek.sign(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
-        }
+        Some(user) => sign_in(&state, user, StatusCode::OK).await,
         None => {
             let body = Json(ErrorOutput::new("Invalid email or password"));
             Ok((StatusCode::FORBIDDEN, body).into_response())
@@ -63,15 +97,345 @@ pub(crate) async fn signin_handler(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/oidc/login",
+    responses(
+        (status = 302, description = "Redirect to the OIDC provider"),
+        (status = 404, description = "OIDC login is not configured", body = ErrorOutput),
+    )
+)]
+/// Sign in with the OIDC provider, the browser is redirected to it.
+///
+/// The state of the login is kept in an HttpOnly cookie, the callback only accepts its own.
+pub(crate) async fn oidc_login_handler(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, AppError> {
+    let login = state.start_oidc_login().await?;
+    let secure = state
+        .config
+        .oidc
+        .as_ref()
+        .is_some_and(|oidc| oidc.redirect_url.starts_with("https://"));
+    // the provider redirects back with a top-level GET, which Lax cookies are sent with
+    let cookie = Cookie::build((OIDC_STATE_COOKIE, login.state))
+        .path(OIDC_COOKIE_PATH)
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax);
+    Ok((jar.add(cookie), Redirect::to(&login.url)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/oidc/callback",
+    params(OidcCallback),
+    responses(
+        (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 202, description = "Two-factor authentication required", body = TwoFactorChallenge),
+        (status = 401, description = "Invalid state, code or id token", body = ErrorOutput),
+    )
+)]
+/// The OIDC provider redirects back here after the user signed in at it.
+///
+/// The first login of an account links it to the user with its verified email, or creates a
+/// user without password in the workspace of the provider.
+pub(crate) async fn oidc_callback_handler(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Query(input): Query<OidcCallback>,
+) -> Result<impl IntoResponse, AppError> {
+    let browser_state = jar.get(OIDC_STATE_COOKIE).map(|cookie| cookie.value());
+    let user = state.finish_oidc_login(&input, browser_state).await?;
+    let jar = jar.remove(Cookie::build(OIDC_STATE_COOKIE).path(OIDC_COOKIE_PATH));
+    Ok((jar, sign_in(&state, user, StatusCode::OK).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/refresh",
+    responses(
+        (status = 200, description = "Tokens refreshed", body = AuthOutput),
+        (status = 401, description = "Invalid, used or expired refresh token", body = ErrorOutput),
+        (status = 403, description = "Email or two-factor requirement of the workspace is not met", body = ErrorOutput),
+    )
+)]
+/// Get a new access token and the next refresh token of the session.
+///
+/// Using a refresh token twice revokes the whole session.
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshSession>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, sid, refresh_token) = state.refresh_session(&input.refresh_token).await?;
+    let token = state.ek.sign(TokenClaims::new(&user, sid))?;
+    Ok(Json(AuthOutput::new(token, Some(refresh_token))))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/signout",
+    responses(
+        (status = 204, description = "Session revoked"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Sign out the session of the access token, its tokens can't be used anymore.
+pub(crate) async fn signout_handler(
+    Extension(claims): Extension<TokenClaims>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_session(claims.sid, claims.uid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/{id}/switch",
+    params(
+        ("id" = u64, Path, description = "Workspace id"),
+    ),
+    responses(
+        (status = 200, description = "Token scoped to the workspace", body = AuthOutput),
+        (status = 403, description = "Not an active member of the workspace, or its email or two-factor requirement is not met", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Switch the session to another workspace of the user and get a token scoped to it.
+///
+/// The refresh token of the session stays the same. The workspace is also the one to sign in
+/// to next time.
+pub(crate) async fn switch_workspace_handler(
+    Extension(claims): Extension<TokenClaims>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.ensure_email_verified(claims.uid, id as _).await?;
+    state.ensure_two_factor(claims.uid, id as _).await?;
+    let user = state
+        .switch_workspace(id, claims.uid as _, claims.sid)
+        .await?;
+    let token = state.ek.sign(TokenClaims::new(&user, claims.sid))?;
+    Ok(Json(AuthOutput::new(token, None)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/verify-email",
+    responses(
+        (status = 204, description = "Email verified"),
+        (status = 400, description = "Invalid, used or expired token", body = ErrorOutput),
+    )
+)]
+/// Verify the email of the user with the token of the verification mail.
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Json(input): Json<VerifyEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    state.verify_email(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/verify-email/resend",
+    responses(
+        (status = 204, description = "Verification mail sent if the email is not verified yet"),
+    )
+)]
+/// Send another verification mail, e.g. after the first one expired.
+pub(crate) async fn resend_verification_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RequestEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    state.resend_verification_email(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/password/forgot",
+    responses(
+        (status = 204, description = "Password reset mail sent if the email has a user"),
+    )
+)]
+/// Mail a token to reset the password of the user with.
+///
+/// It returns 204 for unknown emails too, so it doesn't tell which emails have users.
+pub(crate) async fn forgot_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RequestEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    state.request_password_reset(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/password/reset",
+    responses(
+        (status = 204, description = "Password reset, all sessions signed out"),
+        (status = 400, description = "Invalid, used or expired token", body = ErrorOutput),
+    )
+)]
+/// Set a new password with the token of the password reset mail. All sessions of the user are
+/// signed out.
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_password(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/signin/2fa",
+    responses(
+        (status = 200, description = "User signed in", body = AuthOutput),
+        (status = 401, description = "Invalid code, or challenge used, expired or failed too often", body = ErrorOutput),
+    )
+)]
+/// Finish the signin with the challenge and a code of the authenticator, or a recovery code.
+///
+/// If the user enrolled the authenticator with the challenge, the recovery codes are returned
+/// along with the tokens.
+pub(crate) async fn two_factor_signin_handler(
+    State(state): State<AppState>,
+    Json(input): Json<TwoFactorSignin>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, codes) = state.finish_two_factor(&input).await?;
+    let mut output = start_session(&state, user).await?;
+    output.recovery_codes = codes.map(|codes| codes.recovery_codes);
+    Ok(Json(output))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/2fa/enroll",
+    responses(
+        (status = 200, description = "Secret enrolled, enable it with a code", body = TotpEnrollment),
+        (status = 403, description = "Two-factor authentication is enabled already", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Generate a new secret for the authenticator of the user.
+pub(crate) async fn totp_enroll_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let enrollment = state.enroll_totp(user.id).await?;
+    Ok(Json(enrollment))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/2fa/enable",
+    responses(
+        (status = 200, description = "Two-factor authentication enabled", body = RecoveryCodes),
+        (status = 401, description = "Invalid code", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Enable two-factor authentication with a code of the enrolled authenticator.
+pub(crate) async fn totp_enable_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TotpCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state.enable_totp(user.id, &input).await?;
+    Ok(Json(codes))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/2fa/disable",
+    responses(
+        (status = 204, description = "Two-factor authentication disabled"),
+        (status = 401, description = "Invalid code", body = ErrorOutput),
+        (status = 403, description = "Required by a workspace of the user", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Disable two-factor authentication with a code of the authenticator, or a recovery code.
+pub(crate) async fn totp_disable_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TotpCode>,
+) -> Result<impl IntoResponse, AppError> {
+    state.disable_totp(user.id, &input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/2fa/recovery-codes",
+    responses(
+        (status = 200, description = "New recovery codes, the previous ones are void", body = RecoveryCodes),
+        (status = 401, description = "Invalid code", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Replace the recovery codes, with a code of the authenticator or a recovery code.
+pub(crate) async fn recovery_codes_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TotpCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state.regenerate_recovery_codes(user.id, &input).await?;
+    Ok(Json(codes))
+}
+
+/// Start a session of the user, or challenge it for the second factor first.
+async fn sign_in(state: &AppState, user: User, status: StatusCode) -> Result<Response, AppError> {
+    state.ensure_email_verified(user.id, user.ws_id).await?;
+    if let Some(challenge) = state.start_two_factor(&user).await? {
+        return Ok((StatusCode::ACCEPTED, Json(challenge)).into_response());
+    }
+    let body = Json(start_session(state, user).await?);
+    Ok((status, body).into_response())
+}
+
+async fn start_session(state: &AppState, user: User) -> Result<AuthOutput, AppError> {
+    let (sid, refresh_token) = state.create_session(&user).await?;
+    let token = state.ek.sign(TokenClaims::new(&user, sid))?;
+    Ok(AuthOutput::new(token, Some(refresh_token)))
+}
+
+impl AuthOutput {
+    fn new(token: String, refresh_token: Option<String>) -> Self {
+        Self {
+            token,
+            expires_in: ACCESS_TOKEN_DURATION,
+            refresh_token,
+            recovery_codes: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::TwoFactorChallenge;
     use http_body_util::BodyExt;
 
     #[tokio::test]
     async fn signup_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "hedon", "hedon@example.com", "123456");
+        let input = CreateUser::new("hedon", "hedon", "hedon@example.com", "123456");
         let ret = signup_handler(State(state), Json(input))
             .await?
             .into_response();
@@ -85,7 +449,7 @@ mod tests {
     #[tokio::test]
     async fn signup_duplicated_user_should_409() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "hedon", "hedon@example.com", "123456");
+        let input = CreateUser::new("hedon", "hedon", "hedon@example.com", "123456");
         signup_handler(State(state.clone()), Json(input.clone())).await?;
         let ret = signup_handler(State(state), Json(input))
             .await
@@ -105,7 +469,7 @@ mod tests {
         let email = "hedon@example.com";
         let password = "123456";
 
-        let user = CreateUser::new("none", name, email, password);
+        let user = CreateUser::new("hedon", name, email, password);
         state.create_user(&user).await?;
 
         let input = SigninUser::new(email, password);
@@ -127,7 +491,7 @@ mod tests {
         let email = "hedon@example.com";
         let password = "123456";
 
-        let user = CreateUser::new("none", name, email, password);
+        let user = CreateUser::new("hedon", name, email, password);
         state.create_user(&user).await?;
 
         let input = SigninUser::new(email, "1234567");
@@ -158,4 +522,40 @@ mod tests {
         assert_eq!(ret.error, "Invalid email or password");
         Ok(())
     }
+
+    #[tokio::test]
+    async fn signin_with_two_factor_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE workspaces SET require_two_factor = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+
+        // the workspace requires it, the user enrolls while signing in
+        let input = SigninUser::new("hedon@acme.com", "123456");
+        let ret = signin_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::ACCEPTED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let challenge: TwoFactorChallenge = serde_json::from_slice(&body)?;
+        let totp = totp_rs::TOTP::from_url(challenge.otpauth_uri.as_deref().unwrap())?;
+
+        let input = TwoFactorSignin {
+            challenge_token: challenge.challenge_token.clone(),
+            code: totp.generate_current()?,
+        };
+        let ret = two_factor_signin_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert!(ret.refresh_token.is_some());
+        assert!(ret.recovery_codes.is_some_and(|codes| !codes.is_empty()));
+
+        // the challenge is no access token
+        let claims = state.dk.verify(&challenge.challenge_token)?;
+        assert!(!claims.has_scope(chat_core::Scope::Chat));
+        Ok(())
+    }
 }
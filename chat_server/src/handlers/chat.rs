@@ -4,9 +4,12 @@ use axum::{
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::User;
+use chat_core::{ChatRole, User};
 
-use crate::{models::CreateChat, AppError, AppState};
+use crate::{
+    models::{CreateChat, MarkRead, UpdateChat},
+    AppError, AppState,
+};
 
 #[utoipa::path(
     get,
@@ -18,7 +21,7 @@ use crate::{models::CreateChat, AppError, AppState};
         ("token" = [])
     )
 )]
-/// List all chats in the workspace of the user.
+/// List all chats in the workspace of the user, with the unread message count of each chat.
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
@@ -75,10 +78,128 @@ pub(crate) async fn get_chat_handler(
     }
 }
 
-pub(crate) async fn update_chat_handler() -> impl IntoResponse {
-    "update chat"
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = UpdateChat,
+    responses(
+        (status = 200, description = "Chat updated", body = Chat),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not an owner or admin of the chat", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Update the chat: rename it, add or remove members, or toggle public/private.
+///
+/// Only the owner and admins can update the chat, and only the owner can change admins.
+pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(role): Extension<ChatRole>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.update_chat(id, input, role, user.ws_id as _).await?;
+    Ok(Json(chat))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 204, description = "Chat deleted"),
+        (status = 403, description = "Not an owner or admin of the chat", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Delete the chat together with its messages, only the owner and admins can do this.
+pub(crate) async fn delete_chat_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_chat(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/typing",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 204, description = "Typing signal sent to the other members"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Signal that the user is typing in the chat, clients should resend it every few seconds while typing.
+pub(crate) async fn typing_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.notify_typing(id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/read",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = MarkRead,
+    responses(
+        (status = 200, description = "Read receipt of the user", body = ReadReceipt),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Mark the messages of the chat as read up to a message.
+pub(crate) async fn mark_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<MarkRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let receipt = state.mark_read(input, id, user.id as _).await?;
+    Ok(Json(receipt))
 }
 
-pub(crate) async fn delete_chat_handler() -> impl IntoResponse {
-    "delete chat"
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/read",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 200, description = "Read receipts of the chat members", body = Vec<ReadReceipt>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the read receipts of the chat members.
+pub(crate) async fn list_read_receipt_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let receipts = state.fetch_read_receipts(id).await?;
+    Ok(Json(receipts))
 }
@@ -9,7 +9,7 @@ use tokio::fs;
 use tracing::{info, warn};
 
 use crate::{
-    models::{ChatFile, CreateMessage, ListMessages},
+    models::{ChatFile, CreateMessage, CreateReaction, ListMessages, UpdateMessage},
     AppError, AppState,
 };
 
@@ -46,14 +46,14 @@ pub(crate) async fn send_message_handler(
         ListMessages
     ),
     responses(
-        (status = 200, description = "List of messages", body = Vec<Message>),
+        (status = 200, description = "Page of messages", body = MessagePage),
         (status = 400, description = "Invalid input", body = ErrorOutput),
     ),
     security(
         ("token" = [])
     )
 )]
-/// List all messages in the chat.
+/// List a page of messages in the chat, around a message or a time if asked.
 pub(crate) async fn list_message_handler(
     State(state): State<AppState>,
     Path(id): Path<u64>,
@@ -63,12 +63,175 @@ pub(crate) async fn list_message_handler(
     Ok(Json(messages))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{msg_id}/thread",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Thread root message id"),
+        ListMessages
+    ),
+    responses(
+        (status = 200, description = "Page of thread replies", body = MessagePage),
+        (status = 404, description = "Thread root message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the replies in the thread of a message.
+pub(crate) async fn list_thread_message_handler(
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.list_thread_messages(input, id, msg_id).await?;
+    Ok(Json(messages))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/messages/{msg_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+    ),
+    request_body = UpdateMessage,
+    responses(
+        (status = 200, description = "Message updated", body = Message),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the sender of the message", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Edit a message, only the sender can do this.
+pub(crate) async fn update_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Json(input): Json<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state
+        .update_message(input, id, msg_id, user.id as _)
+        .await?;
+    Ok(Json(msg))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/messages/{msg_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+    ),
+    responses(
+        (status = 200, description = "Message deleted", body = Message),
+        (status = 403, description = "Not the sender of the message", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Delete a message, only the sender can do this. The message is kept as a tombstone.
+pub(crate) async fn delete_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = state.delete_message(id, msg_id, user.id as _).await?;
+    Ok(Json(msg))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/messages/{msg_id}/history",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+    ),
+    responses(
+        (status = 200, description = "Previous versions of the message", body = Vec<MessageEdit>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the edit history of a message, latest edit first.
+pub(crate) async fn list_message_edit_handler(
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let edits = state.list_message_edits(id, msg_id).await?;
+    Ok(Json(edits))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/messages/{msg_id}/reactions",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+    ),
+    request_body = CreateReaction,
+    responses(
+        (status = 201, description = "Reactions of the message", body = Vec<Reaction>),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// React to a message with an emoji.
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id)): Path<(u64, u64)>,
+    Json(input): Json<CreateReaction>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state.add_reaction(input, id, msg_id, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(reactions)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/messages/{msg_id}/reactions/{emoji}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("msg_id" = u64, Path, description = "Message id"),
+        ("emoji" = String, Path, description = "Emoji of the reaction"),
+    ),
+    responses(
+        (status = 200, description = "Reactions of the message", body = Vec<Reaction>),
+        (status = 404, description = "Reaction not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Remove the reaction of the user from a message.
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, msg_id, emoji)): Path<(u64, u64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let reactions = state
+        .remove_reaction(id, msg_id, user.id as _, &emoji)
+        .await?;
+    Ok(Json(reactions))
+}
+
 pub(crate) async fn file_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(i64, String)>,
 ) -> Result<impl IntoResponse, AppError> {
-    if user.ws_id != ws_id {
+    // files of any workspace the user is an active member of
+    if !state.is_workspace_member(ws_id, user.id).await? {
         return Err(AppError::NotFound(
             "File doesn't exists or you don't have permission".to_string(),
         ));
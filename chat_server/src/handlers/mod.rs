@@ -1,11 +1,13 @@
 mod auth;
 mod chat;
 mod messages;
+mod search;
 mod workspace;
 
 pub(crate) use auth::*;
 pub(crate) use chat::*;
 pub(crate) use messages::*;
+pub(crate) use search::*;
 pub(crate) use workspace::*;
 
 use axum::response::IntoResponse;
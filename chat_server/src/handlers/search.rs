@@ -0,0 +1,34 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{models::SearchMessages, AppError, AppState};
+
+#[utoipa::path(
+    get,
+    path = "/api/search",
+    params(
+        SearchMessages
+    ),
+    responses(
+        (status = 200, description = "Matched messages", body = Vec<SearchResult>),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Search messages in all chats of the user, latest first.
+pub(crate) async fn search_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let results = state
+        .search_messages(input, user.id as _, user.ws_id as _)
+        .await?;
+    Ok(Json(results))
+}
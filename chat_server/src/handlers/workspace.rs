@@ -1,7 +1,15 @@
-use axum::{extract::State, response::IntoResponse, Extension, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
 use chat_core::User;
 
-use crate::{AppError, AppState};
+use crate::{
+    models::{CreateInvite, JoinWorkspace, UpdateMember, UpdateWorkspace, UpdateWorkspaceSettings},
+    AppError, AppState,
+};
 
 #[utoipa::path(
     get,
@@ -20,3 +28,261 @@ pub(crate) async fn list_chat_user_handler(
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(users))
 }
+
+#[utoipa::path(
+    get,
+    path = "/api/workspaces",
+    responses(
+        (status = 200, description = "Workspaces the user is an active member of", body = Vec<Workspace>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the workspaces of the user, switch to one of them to get a token scoped to it.
+pub(crate) async fn list_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.list_user_workspaces(user.id as _).await?;
+    Ok(Json(workspaces))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/join",
+    responses(
+        (status = 201, description = "Joined the workspace", body = Workspace),
+        (status = 400, description = "Already a member of the workspace", body = ErrorOutput),
+        (status = 403, description = "Invalid or expired invite", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Join another workspace with an invite, the current token stays scoped to its workspace.
+pub(crate) async fn join_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<JoinWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.join_workspace(&input, &user).await?;
+    Ok((StatusCode::CREATED, Json(ws)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace",
+    responses(
+        (status = 200, description = "Workspace of the user", body = WorkspaceDetail),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Get the workspace of the user with its settings.
+pub(crate) async fn get_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.get_workspace(user.ws_id as _).await?;
+    Ok(Json(ws))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/workspace",
+    responses(
+        (status = 200, description = "Workspace updated", body = WorkspaceDetail),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Rename the workspace or transfer its ownership, only the owner can do this.
+pub(crate) async fn update_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state
+        .update_workspace(input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(ws))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/workspace/settings",
+    responses(
+        (status = 200, description = "Settings updated", body = WorkspaceSettings),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Replace the settings of the workspace, only the owner can do this.
+pub(crate) async fn update_workspace_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspaceSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state
+        .update_workspace_settings(input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/members",
+    responses(
+        (status = 200, description = "List of ws members with roles", body = Vec<WorkspaceMember>),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the members of the workspace with their roles, deactivated ones included.
+pub(crate) async fn list_workspace_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state
+        .list_workspace_members(user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(members))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/workspace/members/{id}",
+    params(
+        ("id" = u64, Path, description = "User id"),
+    ),
+    responses(
+        (status = 200, description = "Member updated", body = WorkspaceMember),
+        (status = 400, description = "The member is the owner", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+        (status = 404, description = "Member not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Deactivate a member or activate it again, only the owner can do this.
+pub(crate) async fn update_workspace_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .update_workspace_member(input, user.ws_id as _, user.id as _, id)
+        .await?;
+    Ok(Json(member))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/workspace/members/{id}",
+    params(
+        ("id" = u64, Path, description = "User id"),
+    ),
+    responses(
+        (status = 204, description = "Member removed"),
+        (status = 400, description = "The member is the owner", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+        (status = 404, description = "Member not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Remove a member from the workspace, it is deactivated and leaves all chats.
+pub(crate) async fn remove_workspace_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .remove_workspace_member(user.ws_id as _, user.id as _, id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/invites",
+    responses(
+        (status = 201, description = "Invite created", body = Invite),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Create an invite to the workspace of the user, only the owner can do this.
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let invite = state
+        .create_invite(input, user.ws_id as _, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/invites",
+    responses(
+        (status = 200, description = "List of ws invites", body = Vec<Invite>),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// List the invites of the workspace of the user, revoked and used ones included.
+pub(crate) async fn list_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let invites = state.list_invites(user.ws_id as _, user.id as _).await?;
+    Ok(Json(invites))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/invites/{id}",
+    params(
+        ("id" = u64, Path, description = "Invite id"),
+    ),
+    responses(
+        (status = 204, description = "Invite revoked"),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+        (status = 404, description = "Invite not found or revoked already", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+/// Revoke an invite, it can't be used to sign up anymore.
+pub(crate) async fn revoke_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .revoke_invite(id, user.ws_id as _, user.id as _)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,22 +1,28 @@
 mod config;
 mod error;
 mod handlers;
+mod mail;
 mod middlewares;
 mod models;
+mod oidc;
 mod openapi;
 
 use handlers::*;
-use middlewares::verify_chat;
+use middlewares::{verify_chat, verify_chat_admin};
 
-use chat_core::{set_layer, verify_token, DecodingKey, EncodingKey, TokenVerify, User};
+use chat_core::{
+    set_layer, verify_token, DecodingKey, EncodingKey, SessionCache, TokenClaims, TokenVerify, User,
+};
 
 use anyhow::Context;
 use axum::{
     http::Method,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
+use mail::{new_mail_sender, MailSender};
+use oidc::OidcClient;
 use openapi::OpenApiRouter;
 use sqlx::PgPool;
 use std::{ops::Deref, sync::Arc};
@@ -37,18 +43,47 @@ pub struct AppStateInner {
     pub dk: DecodingKey,
     pub ek: EncodingKey,
     pub pool: sqlx::PgPool,
+    pub sessions: SessionCache,
+    pub oidc: Option<OidcClient>,
+    pub mailer: Arc<dyn MailSender>,
 }
 
 pub async fn get_router(state: AppState) -> Result<Router, AppError> {
     let chat = Router::new()
         .route(
             "/:id",
-            get(get_chat_handler)
-                .patch(update_chat_handler)
-                .delete(delete_chat_handler)
-                .post(send_message_handler),
+            get(get_chat_handler).post(send_message_handler).merge(
+                patch(update_chat_handler)
+                    .delete(delete_chat_handler)
+                    .layer(from_fn_with_state(state.clone(), verify_chat_admin)),
+            ),
         )
         .route("/:id/messages", get(list_message_handler))
+        .route("/:id/typing", post(typing_handler))
+        .route(
+            "/:id/read",
+            get(list_read_receipt_handler).post(mark_read_handler),
+        )
+        .route(
+            "/:id/messages/:msg_id",
+            patch(update_message_handler).delete(delete_message_handler),
+        )
+        .route(
+            "/:id/messages/:msg_id/history",
+            get(list_message_edit_handler),
+        )
+        .route(
+            "/:id/messages/:msg_id/thread",
+            get(list_thread_message_handler),
+        )
+        .route(
+            "/:id/messages/:msg_id/reactions",
+            post(add_reaction_handler),
+        )
+        .route(
+            "/:id/messages/:msg_id/reactions/:emoji",
+            delete(remove_reaction_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_chat))
         .route("/", get(list_chat_handler).post(create_chat_handler));
 
@@ -65,11 +100,46 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
 
     let api = Router::new()
         .route("/users", get(list_chat_user_handler))
+        .route("/workspaces", get(list_workspace_handler))
+        .route("/workspaces/join", post(join_workspace_handler))
+        .route("/workspaces/:id/switch", post(switch_workspace_handler))
+        .route(
+            "/workspace",
+            get(get_workspace_handler).patch(update_workspace_handler),
+        )
+        .route(
+            "/workspace/settings",
+            put(update_workspace_settings_handler),
+        )
+        .route("/workspace/members", get(list_workspace_member_handler))
+        .route(
+            "/workspace/members/:id",
+            patch(update_workspace_member_handler).delete(remove_workspace_member_handler),
+        )
+        .route(
+            "/invites",
+            get(list_invite_handler).post(create_invite_handler),
+        )
+        .route("/invites/:id", delete(revoke_invite_handler))
         .nest("/chats", chat)
+        .route("/search", get(search_handler))
         .route("/upload", post(upload_handler))
         .route("/files/:ws_id/*path", get(file_handler))
+        .route("/signout", post(signout_handler))
+        .route("/2fa/enroll", post(totp_enroll_handler))
+        .route("/2fa/enable", post(totp_enable_handler))
+        .route("/2fa/disable", post(totp_disable_handler))
+        .route("/2fa/recovery-codes", post(recovery_codes_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+        .route("/refresh", post(refresh_handler))
+        .route("/oidc/login", get(oidc_login_handler))
+        .route("/oidc/callback", get(oidc_callback_handler))
+        .route("/verify-email", post(verify_email_handler))
+        .route("/verify-email/resend", post(resend_verification_handler))
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
         .route("/signin", post(signin_handler))
+        .route("/signin/2fa", post(two_factor_signin_handler))
         .route("/signup", post(signup_handler))
         .layer(cors);
 
@@ -87,17 +157,22 @@ impl AppState {
         fs::create_dir_all(&config.server.base_dir)
             .await
             .context("create base_dir failed")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
+        let dk = DecodingKey::load_all(config.auth.pks()).context("load pk failed")?;
         let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect to db failed")?;
+        let oidc = config.oidc.clone().map(OidcClient::new);
+        let mailer = new_mail_sender(&config.mail)?;
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
                 dk,
                 ek,
                 pool,
+                sessions: SessionCache::default(),
+                oidc,
+                mailer,
             }),
         })
     }
@@ -112,9 +187,21 @@ impl Deref for AppState {
 
 impl TokenVerify for AppState {
     type Error = AppError;
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
+    fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
         Ok(self.dk.verify(token)?)
     }
+
+    async fn verify_session(&self, claims: &TokenClaims) -> Result<User, Self::Error> {
+        self.sessions
+            .load(claims, &self.pool)
+            .await?
+            .ok_or_else(|| {
+                AppError::InvalidSession(format!(
+                    "Session {} is revoked, or user {} is not an active member of workspace {}",
+                    claims.sid, claims.uid, claims.ws_id
+                ))
+            })
+    }
 }
 
 #[cfg(feature = "test-util")]
@@ -128,18 +215,26 @@ mod test_util {
 
     impl AppState {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
-            let config = AppConfig::load()?;
-            let dk = DecodingKey::load(&config.auth.pk).context("load dk failed")?;
+            Self::new_for_test_with(AppConfig::load()?).await
+        }
+
+        pub async fn new_for_test_with(config: AppConfig) -> Result<(TestPg, Self), AppError> {
+            let dk = DecodingKey::load_all(config.auth.pks()).context("load dk failed")?;
             let ek = EncodingKey::load(&config.auth.sk).context("load ek failed")?;
             let server_url = config.server.db_url.split('/').nth(2).unwrap();
             let (tdb, pool) =
                 get_test_pool(Some(format!("postgres://{server_url}").as_str())).await;
+            let oidc = config.oidc.clone().map(OidcClient::new);
+            let mailer = new_mail_sender(&config.mail)?;
             let state = Self {
                 inner: Arc::new(AppStateInner {
                     config,
                     dk,
                     ek,
                     pool,
+                    sessions: SessionCache::default(),
+                    oidc,
+                    mailer,
                 }),
             };
             Ok((tdb, state))
@@ -0,0 +1,158 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures_util::future::BoxFuture;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::fs;
+use tracing::info;
+
+use crate::{
+    config::{MailConfig, MailTransport},
+    AppError,
+};
+
+/// A plain text mail to a user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends the mails of the server, e.g. the tokens to verify an email or to reset a password.
+pub trait MailSender: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>>;
+}
+
+/// Create the sender of the configured transport.
+pub fn new_mail_sender(config: &MailConfig) -> Result<Arc<dyn MailSender>, AppError> {
+    let sender: Arc<dyn MailSender> = match &config.transport {
+        MailTransport::Smtp { url } => Arc::new(SmtpMailSender::new(&config.from, url)?),
+        MailTransport::File { dir } => Arc::new(FileMailSender {
+            from: config.from.clone(),
+            dir: Some(dir.clone()),
+        }),
+        MailTransport::Log => Arc::new(FileMailSender {
+            from: config.from.clone(),
+            dir: None,
+        }),
+    };
+    Ok(sender)
+}
+
+pub struct SmtpMailSender {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+/// Writes each mail to a file in the dir, or to the log without a dir. Mails are not delivered,
+/// the tokens in them are only meant for local testing.
+pub struct FileMailSender {
+    from: String,
+    dir: Option<PathBuf>,
+}
+
+impl SmtpMailSender {
+    pub fn new(from: &str, url: &str) -> Result<Self, AppError> {
+        let from = from.parse().context("invalid mail sender")?;
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .context("invalid smtp url")?
+            .build();
+        Ok(Self { from, transport })
+    }
+}
+
+impl MailSender for SmtpMailSender {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let to = mail
+                .to
+                .parse()
+                .with_context(|| format!("invalid recipient {}", mail.to))?;
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(&mail.subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(mail.body.clone())
+                .context("build mail failed")?;
+            self.transport
+                .send(message)
+                .await
+                .context("send mail failed")?;
+            Ok(())
+        })
+    }
+}
+
+impl MailSender for FileMailSender {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let Some(dir) = &self.dir else {
+                info!(to = mail.to, subject = mail.subject, "mail: {}", mail.body);
+                return Ok(());
+            };
+
+            // sorted by the time they were sent for each recipient
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            fs::create_dir_all(dir).await?;
+            let content = format!(
+                "From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+                self.from, mail.to, mail.subject, mail.body
+            );
+            let name = format!("{}-{nanos}.txt", file_safe(&mail.to));
+            fs::write(dir.join(name), content).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Keep the recipient readable in the file name, but without the characters of a path.
+fn file_safe(to: &str) -> String {
+    to.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '@' | '.' | '_' | '+' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_mail_sender_should_stay_in_dir() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir()
+            .join("chat_server_mails")
+            .join(crate::models::generate_token());
+        let sender = FileMailSender {
+            from: "Chat <noreply@localhost>".to_string(),
+            dir: Some(dir.join("mails")),
+        };
+        let mail = Mail {
+            to: "../../x/tchen@acme.org".to_string(),
+            subject: "Hi".to_string(),
+            body: "Hello".to_string(),
+        };
+        sender.send(&mail).await?;
+
+        let names: Vec<_> = std::fs::read_dir(dir.join("mails"))?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with(".._.._x_tchen@acme.org-"));
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+        Ok(())
+    }
+}
@@ -3,19 +3,27 @@ use axum::{
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use chat_core::User;
+use chat_core::{ChatRole, User};
+use serde::Deserialize;
 
 use crate::{AppError, AppState};
 
+/// Chat routes may carry more path params (e.g. message id), only the chat id matters here.
+#[derive(Debug, Deserialize)]
+struct ChatPath {
+    id: u64,
+}
+
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
-        .await
-        .unwrap();
+    let chat_id = match Path::<ChatPath>::from_request_parts(&mut parts, &state).await {
+        Ok(Path(path)) => path.id,
+        Err(e) => return e.into_response(),
+    };
 
     let user = parts.extensions.get::<User>().unwrap();
     if !state
-        .is_chat_member(chat_id, user.id as _)
+        .is_chat_member(chat_id, user.id as _, user.ws_id as _)
         .await
         .unwrap_or_default()
     {
@@ -30,15 +38,46 @@ pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next
     next.run(req).await
 }
 
+/// Only chat owner and admins can pass, the role is attached to the request for handlers.
+pub async fn verify_chat_admin(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let chat_id = match Path::<ChatPath>::from_request_parts(&mut parts, &state).await {
+        Ok(Path(path)) => path.id,
+        Err(e) => return e.into_response(),
+    };
+
+    let user = parts.extensions.get::<User>().unwrap();
+    let role = match state.get_chat_role(chat_id, user.id as _).await {
+        Ok(Some(role @ (ChatRole::Owner | ChatRole::Admin))) => role,
+        Ok(_) => {
+            let err = AppError::PermissionDenied(format!(
+                "User {} is not an owner or admin of chat {chat_id}",
+                user.id
+            ));
+            return err.into_response();
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(role);
+    next.run(req).await
+}
+
 #[cfg(test)]
 mod tests {
     use axum::{
         body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
     };
-    use chat_core::verify_token;
+    use chat_core::{verify_token, TokenClaims};
     use tower::ServiceExt;
 
     use super::*;
+    use crate::models::UpdateMember;
 
     async fn handler(_req: Request) -> impl IntoResponse {
         (StatusCode::OK, "ok")
@@ -52,13 +91,15 @@ mod tests {
             .find_user_by_id(1)
             .await?
             .expect("user 1 should exist");
-        let token = state.ek.sign(user)?;
+        let (sid, _) = state.create_session(&user).await?;
+        let token = state.ek.sign(TokenClaims::new(&user, sid))?;
 
         let app = Router::new()
             .route("/chat/:id/messages", get(handler))
+            .route("/chat/:id/messages/:msg_id", get(handler))
             .layer(from_fn_with_state(state.clone(), verify_chat))
             .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
-            .with_state(state);
+            .with_state(state.clone());
 
         // user in chat
         let req = Request::builder()
@@ -68,6 +109,14 @@ mod tests {
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::OK);
 
+        // nested path params
+        let req = Request::builder()
+            .uri("/chat/1/messages/1")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
         // user not in chat
         let req = Request::builder()
             .uri("/chat/5/messages")
@@ -75,6 +124,53 @@ mod tests {
             .body(Body::empty())?;
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // user 5 joined workspace foo and is deactivated in acme, still in the chats of acme
+        sqlx::query("INSERT INTO workspace_members(ws_id, user_id) VALUES (2, 5)")
+            .execute(&state.pool)
+            .await?;
+        let input = UpdateMember { active: false };
+        state.update_workspace_member(input, 1, 1, 5).await?;
+        let mut user = state.find_user_by_id(5).await?.unwrap();
+        user.ws_id = 2;
+        let (sid, _) = state.create_session(&user).await?;
+        let token = state.ek.sign(TokenClaims::new(&user, sid))?;
+        let req = Request::builder()
+            .uri("/chat/1/messages")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_chat_admin_middleware_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let app = Router::new()
+            .route("/chat/:id", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat_admin))
+            .layer(from_fn_with_state(state.clone(), verify_chat))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state.clone());
+
+        // user 1 owns chat 1, user 2 is admin, user 3 is a plain member
+        for (user_id, status) in [
+            (1, StatusCode::OK),
+            (2, StatusCode::OK),
+            (3, StatusCode::FORBIDDEN),
+        ] {
+            let user = state.find_user_by_id(user_id).await?.unwrap();
+            let (sid, _) = state.create_session(&user).await?;
+            let token = state.ek.sign(TokenClaims::new(&user, sid))?;
+            let req = Request::builder()
+                .uri("/chat/1")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), status);
+        }
         Ok(())
     }
 }
@@ -1,3 +1,3 @@
 mod chat;
 
-pub use chat::verify_chat;
+pub use chat::{verify_chat, verify_chat_admin};
@@ -1,5 +1,5 @@
 use crate::{AppError, AppState};
-use chat_core::{Chat, ChatType};
+use chat_core::{Chat, ChatRole, ChatType};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
@@ -10,6 +10,28 @@ pub struct CreateChat {
     pub public: bool,
 }
 
+#[derive(Debug, Clone, ToSchema, Default, Serialize, Deserialize)]
+pub struct UpdateChat {
+    /// New name of the chat, keep the current one if not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Members to be added to the chat
+    #[serde(default)]
+    pub add_members: Vec<i64>,
+    /// Members to be removed from the chat
+    #[serde(default)]
+    pub remove_members: Vec<i64>,
+    /// Toggle a named chat between public and private channel
+    #[serde(default)]
+    pub public: Option<bool>,
+    /// Members to be promoted to admin, only the owner can do this
+    #[serde(default)]
+    pub add_admins: Vec<i64>,
+    /// Admins to be demoted to member, only the owner can do this
+    #[serde(default)]
+    pub remove_admins: Vec<i64>,
+}
+
 #[allow(unused)]
 impl AppState {
     pub async fn create_chat(
@@ -18,13 +40,6 @@ impl AppState {
         user_id: u64,
         ws_id: u64,
     ) -> Result<Chat, AppError> {
-        let len = input.members.len();
-        if len < 2 {
-            return Err(AppError::CreateChatError(
-                "Chat must have at least 2 members".to_string(),
-            ));
-        }
-
         // if user id is not in members, reject
         if !input.members.contains(&(user_id as i64)) {
             return Err(AppError::CreateChatError(
@@ -32,43 +47,19 @@ impl AppState {
             ));
         }
 
-        if let Some(name) = &input.name {
-            if name.len() < 3 {
-                return Err(AppError::CreateChatError(
-                    "Chat name must be at least 3 characters".to_string(),
-                ));
-            }
-        }
-
-        if len > 8 && input.name.is_none() {
-            return Err(AppError::CreateChatError(
-                "Chat must have a name if it has more than 8 members".to_string(),
-            ));
-        }
-
-        // verify if all members exist
-        let users = self.fetch_chat_user_by_id(&input.members).await?;
-        if users.len() != len {
-            return Err(AppError::CreateChatError(
-                "Some members don't exist".to_string(),
-            ));
-        }
+        self.verify_chat_input(input.name.as_deref(), &input.members, ws_id)
+            .await
+            .map_err(AppError::CreateChatError)?;
 
         // get chat name and type
-        let chat_type = match (&input.name, len) {
-            (None, 2) => ChatType::Single,
-            (None, _) => ChatType::Group,
-            (Some(_), _) => {
-                if input.public {
-                    ChatType::PublicChannel
-                } else {
-                    ChatType::PrivateChannel
-                }
-            }
-        };
+        let chat_type = get_chat_type(input.name.as_deref(), input.members.len(), input.public);
+        if chat_type == ChatType::PublicChannel {
+            self.verify_public_channels(ws_id).await?;
+        }
 
-        // create chat
-        let chat: Chat = sqlx::query_as(
+        // create chat, the creator becomes the owner
+        let mut tx = self.pool.begin().await?;
+        let mut chat: Chat = sqlx::query_as(
             r#"INSERT INTO chats(ws_id, name, type, members)
             VALUES($1, $2, $3, $4)
             RETURNING id, ws_id, name, type, members, created_at"#,
@@ -77,18 +68,39 @@ impl AppState {
         .bind(input.name)
         .bind(chat_type)
         .bind(input.members)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
 
+        sqlx::query(
+            r#"INSERT INTO chat_roles(chat_id, user_id, role)
+            VALUES($1, $2, 'owner')"#,
+        )
+        .bind(chat.id)
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        chat.owner_id = Some(user_id as _);
         Ok(chat)
     }
 
     pub async fn fetch_chats(&self, user_id: u64, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members,created_at
+            SELECT id, ws_id, name, type, members, created_at,
+                (SELECT user_id FROM chat_roles
+                 WHERE chat_id = chats.id AND role = 'owner') AS owner_id,
+                ARRAY(SELECT user_id FROM chat_roles
+                 WHERE chat_id = chats.id AND role = 'admin' ORDER BY user_id) AS admins,
+                (SELECT count(*) FROM messages m
+                 WHERE m.chat_id = chats.id AND m.sender_id <> $2 AND m.deleted_at IS NULL
+                    AND m.id > COALESCE((SELECT last_read_id FROM read_receipts r
+                        WHERE r.chat_id = chats.id AND r.user_id = $2), 0)) AS unread_count
             FROM chats
-            WHERE ws_id = $1 AND $2 = ANY(members)"#,
+            WHERE ws_id = $1 AND $2 = ANY(members)
+                AND EXISTS (SELECT 1 FROM workspace_members
+                    WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NULL)"#,
         )
         .bind(ws_id as i64)
         .bind(user_id as i64)
@@ -101,7 +113,11 @@ impl AppState {
     pub async fn get_chat_by_id(&self, chat_id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_at,
+                (SELECT user_id FROM chat_roles
+                 WHERE chat_id = chats.id AND role = 'owner') AS owner_id,
+                ARRAY(SELECT user_id FROM chat_roles
+                 WHERE chat_id = chats.id AND role = 'admin' ORDER BY user_id) AS admins
             FROM chats
             WHERE id = $1"#,
         )
@@ -112,21 +128,251 @@ impl AppState {
         Ok(chat)
     }
 
-    pub async fn is_chat_member(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
+    /// Members of a chat of the workspace of their token can access it, as long as they are
+    /// active members of the workspace.
+    pub async fn is_chat_member(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<bool, AppError> {
         let is_member = sqlx::query(
             r#"
             SELECT 1
-            FROM chats
-            WHERE id = $1 AND $2 = ANY(members)
+            FROM chats c
+            JOIN workspace_members m
+                ON m.ws_id = c.ws_id AND m.user_id = $2 AND m.deactivated_at IS NULL
+            WHERE c.id = $1 AND c.ws_id = $3 AND $2 = ANY(c.members)
             "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
+        .bind(ws_id as i64)
         .fetch_optional(&self.pool)
         .await?;
 
         Ok(is_member.is_some())
     }
+
+    /// Get the role of the user in the chat, `None` if the user is not a member.
+    pub async fn get_chat_role(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Option<ChatRole>, AppError> {
+        let role = sqlx::query_scalar(
+            r#"
+            SELECT COALESCE(r.role, 'member')
+            FROM chats c
+            LEFT JOIN chat_roles r ON r.chat_id = c.id AND r.user_id = $2
+            WHERE c.id = $1 AND $2 = ANY(c.members)
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(role)
+    }
+
+    pub async fn update_chat(
+        &self,
+        chat_id: u64,
+        input: UpdateChat,
+        role: ChatRole,
+        ws_id: u64,
+    ) -> Result<Chat, AppError> {
+        let Some(chat) = self.get_chat_by_id(chat_id).await? else {
+            return Err(AppError::NotFound(format!("chat id {chat_id}")));
+        };
+
+        if (!input.add_admins.is_empty() || !input.remove_admins.is_empty())
+            && role != ChatRole::Owner
+        {
+            return Err(AppError::PermissionDenied(
+                "Only chat owner can change admins".to_string(),
+            ));
+        }
+
+        if let Some(owner_id) = chat.owner_id {
+            if input.remove_members.contains(&owner_id) {
+                return Err(AppError::UpdateChatError(
+                    "Chat owner can't be removed".to_string(),
+                ));
+            }
+        }
+
+        let name = input.name.or(chat.name);
+        let mut members: Vec<i64> = chat
+            .members
+            .into_iter()
+            .filter(|id| !input.remove_members.contains(id))
+            .collect();
+        for id in input.add_members {
+            if !members.contains(&id) {
+                members.push(id);
+            }
+        }
+
+        self.verify_chat_input(name.as_deref(), &members, ws_id)
+            .await
+            .map_err(AppError::UpdateChatError)?;
+
+        if input.public.is_some() && name.is_none() {
+            return Err(AppError::UpdateChatError(
+                "Only named chat can be public or private".to_string(),
+            ));
+        }
+        let public = input
+            .public
+            .unwrap_or(chat.r#type == ChatType::PublicChannel);
+        let chat_type = get_chat_type(name.as_deref(), members.len(), public);
+        if chat_type == ChatType::PublicChannel && chat.r#type != ChatType::PublicChannel {
+            self.verify_public_channels(ws_id).await?;
+        }
+
+        if input.add_admins.iter().any(|id| !members.contains(id)) {
+            return Err(AppError::UpdateChatError(
+                "Admins must be chat members".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        // removed members lose their roles, the owner role is never touched here
+        let demoted: Vec<i64> = input
+            .remove_members
+            .into_iter()
+            .chain(input.remove_admins)
+            .collect();
+        sqlx::query(
+            r#"
+            DELETE FROM chat_roles
+            WHERE chat_id = $1 AND user_id = ANY($2) AND role <> 'owner'"#,
+        )
+        .bind(chat_id as i64)
+        .bind(&demoted)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO chat_roles(chat_id, user_id, role)
+            SELECT $1, UNNEST($2::bigint[]), 'admin'
+            ON CONFLICT (chat_id, user_id) DO NOTHING"#,
+        )
+        .bind(chat_id as i64)
+        .bind(&input.add_admins)
+        .execute(&mut *tx)
+        .await?;
+
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET name = $1, type = $2, members = $3
+            WHERE id = $4
+            RETURNING id, ws_id, name, type, members, created_at,
+                (SELECT user_id FROM chat_roles
+                 WHERE chat_id = chats.id AND role = 'owner') AS owner_id,
+                ARRAY(SELECT user_id FROM chat_roles
+                 WHERE chat_id = chats.id AND role = 'admin' ORDER BY user_id) AS admins"#,
+        )
+        .bind(name)
+        .bind(chat_type)
+        .bind(members)
+        .bind(chat_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    pub async fn delete_chat(&self, chat_id: u64) -> Result<(), AppError> {
+        // messages reference the chat, so they have to go together with it.
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM messages WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        let ret = sqlx::query("DELETE FROM chats WHERE id = $1")
+            .bind(chat_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("chat id {chat_id}")));
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Tell the other members that the user is typing in the chat. The signal is ephemeral,
+    /// it goes through the notification channel only and nothing is stored.
+    pub async fn notify_typing(&self, chat_id: u64, user_id: u64) -> Result<(), AppError> {
+        Chat::notify_typing(&self.pool, chat_id as i64, user_id as i64).await?;
+        Ok(())
+    }
+
+    /// The owner of the workspace can disable new public channels in its settings.
+    async fn verify_public_channels(&self, ws_id: u64) -> Result<(), AppError> {
+        let ws = self.get_workspace(ws_id).await?;
+        if !ws.settings.public_channels {
+            return Err(AppError::PermissionDenied(
+                "Public channels are disabled in the workspace".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// verify the name and members of a chat, shared by chat creation and update.
+    async fn verify_chat_input(
+        &self,
+        name: Option<&str>,
+        members: &[i64],
+        ws_id: u64,
+    ) -> Result<(), String> {
+        let len = members.len();
+        if len < 2 {
+            return Err("Chat must have at least 2 members".to_string());
+        }
+
+        if let Some(name) = name {
+            if name.len() < 3 {
+                return Err("Chat name must be at least 3 characters".to_string());
+            }
+        }
+
+        if len > 8 && name.is_none() {
+            return Err("Chat must have a name if it has more than 8 members".to_string());
+        }
+
+        // verify if all members exist in the workspace
+        let users = self
+            .fetch_ws_chat_user_by_id(members, ws_id)
+            .await
+            .map_err(|e| e.to_string())?;
+        if users.len() != len {
+            return Err("Some members don't exist".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+fn get_chat_type(name: Option<&str>, len: usize, public: bool) -> ChatType {
+    match (name, len) {
+        (None, 2) => ChatType::Single,
+        (None, _) => ChatType::Group,
+        (Some(_), _) => {
+            if public {
+                ChatType::PublicChannel
+            } else {
+                ChatType::PrivateChannel
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -149,6 +395,7 @@ impl CreateChat {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sqlx::postgres::PgListener;
 
     #[tokio::test]
     async fn create_single_chat_should_word() -> anyhow::Result<()> {
@@ -159,6 +406,10 @@ mod tests {
         assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 2);
         assert_eq!(chat.r#type, ChatType::Single);
+        assert_eq!(chat.owner_id, Some(1));
+
+        let role = state.get_chat_role(chat.id as _, 1).await?;
+        assert_eq!(role, Some(ChatRole::Owner));
         Ok(())
     }
 
@@ -189,6 +440,8 @@ mod tests {
         assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 5);
         assert_eq!(chat.r#type, ChatType::PublicChannel);
+        assert_eq!(chat.owner_id, Some(1));
+        assert_eq!(chat.admins, vec![2]);
 
         Ok(())
     }
@@ -206,30 +459,247 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_chat_with_member_outside_workspace_should_fail() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // user 1 is in workspace 1, so chat in workspace 2 should be rejected
+        let input = CreateChat::new("", &[1, 2], false);
+        let err = state.create_chat(input, 1, 2).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: Some members don't exist"
+        );
+        Ok(())
+    }
+
+    // (1, 'private', 'private_channel', '{1,2,3}'),
+    #[tokio::test]
+    async fn update_chat_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = UpdateChat {
+            name: Some("private1".to_string()),
+            add_members: vec![4, 5],
+            remove_members: vec![3],
+            public: Some(true),
+            ..Default::default()
+        };
+        let chat = state.update_chat(2, input, ChatRole::Owner, 1).await?;
+        assert_eq!(chat.name.as_deref(), Some("private1"));
+        assert_eq!(chat.members, vec![1, 2, 4, 5]);
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+
+        // single chat becomes a group when a member is added
+        let input = UpdateChat {
+            add_members: vec![3],
+            ..Default::default()
+        };
+        let chat = state.update_chat(3, input, ChatRole::Owner, 1).await?;
+        assert_eq!(chat.name, None);
+        assert_eq!(chat.members, vec![1, 2, 3]);
+        assert_eq!(chat.r#type, ChatType::Group);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_keep_invariants() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = UpdateChat {
+            remove_members: vec![2],
+            ..Default::default()
+        };
+        let err = state
+            .update_chat(3, input, ChatRole::Owner, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat must have at least 2 members"
+        );
+
+        let input = UpdateChat {
+            name: Some("ab".to_string()),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat(1, input, ChatRole::Owner, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat name must be at least 3 characters"
+        );
+
+        let input = UpdateChat {
+            add_members: vec![100],
+            ..Default::default()
+        };
+        let err = state
+            .update_chat(1, input, ChatRole::Owner, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Some members don't exist"
+        );
+
+        let input = UpdateChat {
+            public: Some(true),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat(4, input, ChatRole::Owner, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Only named chat can be public or private"
+        );
+
+        let err = state
+            .update_chat(10, UpdateChat::default(), ChatRole::Owner, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    // (1, 'general', 'public_channel', '{1,2,3,4,5}'), owner 1, admin 2
+    #[tokio::test]
+    async fn update_chat_roles_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = UpdateChat {
+            add_admins: vec![3, 4],
+            remove_admins: vec![2],
+            ..Default::default()
+        };
+        let chat = state.update_chat(1, input, ChatRole::Owner, 1).await?;
+        assert_eq!(chat.owner_id, Some(1));
+        assert_eq!(chat.admins, vec![3, 4]);
+
+        // removed admin loses the role
+        let input = UpdateChat {
+            remove_members: vec![3],
+            ..Default::default()
+        };
+        let chat = state.update_chat(1, input, ChatRole::Admin, 1).await?;
+        assert_eq!(chat.admins, vec![4]);
+        assert_eq!(state.get_chat_role(1, 3).await?, None);
+        assert_eq!(state.get_chat_role(1, 5).await?, Some(ChatRole::Member));
+
+        // only owner can change admins
+        let input = UpdateChat {
+            add_admins: vec![5],
+            ..Default::default()
+        };
+        let err = state
+            .update_chat(1, input, ChatRole::Admin, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // owner can't be removed
+        let input = UpdateChat {
+            remove_members: vec![1],
+            ..Default::default()
+        };
+        let err = state
+            .update_chat(1, input, ChatRole::Admin, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update chat error: Chat owner can't be removed"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_chat_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // chat 1 has messages
+        state.delete_chat(1).await?;
+        assert!(state.get_chat_by_id(1).await?.is_none());
+
+        let err = state.delete_chat(1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn chat_is_member_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
 
         // user 1 in chat 1
         let is_member = state
-            .is_chat_member(1, 1)
+            .is_chat_member(1, 1, 1)
             .await
             .expect("is chat member failed");
 
         assert!(is_member);
 
         // user 6 doesn't exist
-        let is_member = state.is_chat_member(1, 6).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(1, 6, 1)
+            .await
+            .expect("is member failed");
         assert!(!is_member);
 
         // chat 10 doesn't exist
-        let is_member = state.is_chat_member(10, 1).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(10, 1, 1)
+            .await
+            .expect("is member failed");
         assert!(!is_member);
 
         // user 4 is not a member of chat 2
-        let is_member = state.is_chat_member(2, 4).await.expect("is member failed");
+        let is_member = state
+            .is_chat_member(2, 4, 1)
+            .await
+            .expect("is member failed");
         assert!(!is_member);
 
+        // chat 1 is not in workspace 2
+        let is_member = state
+            .is_chat_member(1, 1, 2)
+            .await
+            .expect("is member failed");
+        assert!(!is_member);
+
+        // user 5 is deactivated in the workspace of chat 1
+        sqlx::query(
+            "UPDATE workspace_members SET deactivated_at = now() WHERE ws_id = 1 AND user_id = 5",
+        )
+        .execute(&state.pool)
+        .await?;
+        let is_member = state
+            .is_chat_member(1, 5, 1)
+            .await
+            .expect("is member failed");
+        assert!(!is_member);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notify_typing_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut listener = PgListener::connect_with(&state.pool).await?;
+        listener.listen("chat_typing").await?;
+
+        state.notify_typing(3, 1).await?;
+        let notif = listener.recv().await?;
+        let mut payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+        let event_id = payload["event_id"].take();
+        assert!(event_id.is_i64());
+        assert_eq!(
+            payload,
+            serde_json::json!({"event_id": null, "chat_id": 3, "user_id": 1})
+        );
         Ok(())
     }
 }
@@ -0,0 +1,408 @@
+use chat_core::{Scope, TokenClaims, User};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{mail::Mail, models::hash_password, AppError, AppState};
+
+/// Seconds until a mailed token expires.
+const VERIFY_EMAIL_DURATION: u64 = 60 * 60 * 24;
+const RESET_PASSWORD_DURATION: u64 = 60 * 60;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct RequestEmail {
+    /// Email of the user to mail the token to
+    pub email: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct VerifyEmail {
+    /// Token from the verification mail
+    pub token: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ResetPassword {
+    /// Token from the password reset mail
+    pub token: String,
+    /// New password of the user
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "email_token_purpose", rename_all = "snake_case")]
+enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl AppState {
+    /// Mail a token to the user to verify its email with.
+    pub async fn send_verification_email(&self, user: &User) -> Result<(), AppError> {
+        let purpose = EmailTokenPurpose::VerifyEmail;
+        let token = self.issue_email_token(user, purpose).await?;
+        let mail = Mail {
+            to: user.email.clone(),
+            subject: "Verify your email".to_string(),
+            body: format!(
+                "Verify your email at {}/verify-email?token={token}\n\nThe link expires in {} hours.",
+                self.config.mail.app_url,
+                purpose.duration() / 3600
+            ),
+        };
+        self.mailer.send(&mail).await
+    }
+
+    /// Mail a new verification token, unless the email is unknown or verified already. The
+    /// result is the same either way, so it doesn't tell which emails have users: a failed mail
+    /// is only logged.
+    pub async fn resend_verification_email(&self, input: &RequestEmail) -> Result<(), AppError> {
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, created_at
+            FROM users
+            WHERE email = $1 AND email_verified_at IS NULL
+            "#,
+        )
+        .bind(&input.email)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(user) = user {
+            if let Err(e) = self.send_verification_email(&user).await {
+                warn!("send verification mail to user {} failed: {e}", user.id);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn verify_email(&self, input: &VerifyEmail) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let user_id = self
+            .consume_email_token(&mut tx, &input.token, EmailTokenPurpose::VerifyEmail)
+            .await?;
+        mark_email_verified(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Mail a token to reset the password with, unless the email is unknown. The result is the
+    /// same either way, so it doesn't tell which emails have users: a failed mail is only logged.
+    pub async fn request_password_reset(&self, input: &RequestEmail) -> Result<(), AppError> {
+        if let Some(user) = self.find_user_by_email(&input.email).await? {
+            if let Err(e) = self.send_password_reset_email(&user).await {
+                warn!("send password reset mail to user {} failed: {e}", user.id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_password_reset_email(&self, user: &User) -> Result<(), AppError> {
+        let purpose = EmailTokenPurpose::ResetPassword;
+        let token = self.issue_email_token(user, purpose).await?;
+        let mail = Mail {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Reset your password at {}/reset-password?token={token}\n\nThe link expires in {} minutes. Ignore this mail if you didn't ask to reset your password.",
+                self.config.mail.app_url,
+                purpose.duration() / 60
+            ),
+        };
+        self.mailer.send(&mail).await
+    }
+
+    /// Set the new password and sign the user out everywhere. Receiving the token proves the
+    /// email too.
+    pub async fn reset_password(&self, input: &ResetPassword) -> Result<(), AppError> {
+        let password_hash = hash_password(&input.password)?;
+        let mut tx = self.pool.begin().await?;
+        let purpose = EmailTokenPurpose::ResetPassword;
+        let user_id = self
+            .consume_email_token(&mut tx, &input.token, purpose)
+            .await?;
+
+        sqlx::query("UPDATE users SET password_hash = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(password_hash)
+            .execute(&mut *tx)
+            .await?;
+        mark_email_verified(&mut tx, user_id).await?;
+        // the other reset tokens mailed before are of no use anymore
+        sqlx::query(
+            r#"
+            UPDATE email_tokens
+            SET used_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await?;
+        let sids: Vec<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE sessions
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for sid in sids {
+            self.sessions.remove(sid);
+        }
+        Ok(())
+    }
+
+    /// Users with an unverified email can't sign in to workspaces requiring a verified one.
+    pub async fn ensure_email_verified(&self, user_id: i64, ws_id: i64) -> Result<(), AppError> {
+        let verified: Option<bool> = sqlx::query_scalar(
+            r#"
+            SELECT u.email_verified_at IS NOT NULL OR NOT w.require_verified_email
+            FROM users u, workspaces w
+            WHERE u.id = $1 AND w.id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if !verified.unwrap_or(false) {
+            return Err(AppError::EmailNotVerified(format!(
+                "Workspace {ws_id} requires a verified email, check the verification mail"
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn issue_email_token(
+        &self,
+        user: &User,
+        purpose: EmailTokenPurpose,
+    ) -> Result<String, AppError> {
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO email_tokens(user_id, purpose, email, expires_at)
+            VALUES($1, $2, $3, CURRENT_TIMESTAMP + $4 * INTERVAL '1 second')
+            RETURNING id
+            "#,
+        )
+        .bind(user.id)
+        .bind(purpose)
+        .bind(&user.email)
+        .bind(purpose.duration() as f64)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let claims = TokenClaims {
+            scopes: vec![purpose.scope()],
+            ..TokenClaims::new(user, id)
+        };
+        Ok(self.ek.sign_for(claims, purpose.duration())?)
+    }
+
+    /// Mark the token used, returns the id of its user. Tokens are only valid once, and only for
+    /// the email they were sent to.
+    async fn consume_email_token(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        token: &str,
+        purpose: EmailTokenPurpose,
+    ) -> Result<i64, AppError> {
+        let claims = self
+            .dk
+            .verify(token)
+            .map_err(|e| AppError::EmailTokenError(e.to_string()))?;
+        if !claims.has_scope(purpose.scope()) {
+            return Err(AppError::EmailTokenError(format!(
+                "Not a token to {purpose:?}"
+            )));
+        }
+
+        let user_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE email_tokens t
+            SET used_at = CURRENT_TIMESTAMP
+            FROM users u
+            WHERE t.id = $1 AND t.user_id = $2 AND t.purpose = $3
+                AND t.used_at IS NULL AND t.expires_at > CURRENT_TIMESTAMP
+                AND u.id = t.user_id AND u.email = t.email
+            RETURNING t.user_id
+            "#,
+        )
+        .bind(claims.sid)
+        .bind(claims.uid)
+        .bind(purpose)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        user_id.ok_or_else(|| AppError::EmailTokenError("Token used or expired".to_string()))
+    }
+}
+
+impl EmailTokenPurpose {
+    fn scope(self) -> Scope {
+        match self {
+            Self::VerifyEmail => Scope::VerifyEmail,
+            Self::ResetPassword => Scope::ResetPassword,
+        }
+    }
+
+    fn duration(self) -> u64 {
+        match self {
+            Self::VerifyEmail => VERIFY_EMAIL_DURATION,
+            Self::ResetPassword => RESET_PASSWORD_DURATION,
+        }
+    }
+}
+
+pub(crate) async fn mark_email_verified(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET email_verified_at = COALESCE(email_verified_at, CURRENT_TIMESTAMP)
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{config::MailTransport, models::SigninUser, AppConfig};
+
+    /// State mailing to files in a dir of its own.
+    async fn new_state() -> anyhow::Result<(sqlx_db_tester::TestPg, AppState, PathBuf)> {
+        let mut config = AppConfig::load()?;
+        let dir = std::env::temp_dir()
+            .join("chat_server_mails")
+            .join(crate::models::generate_token());
+        config.mail.transport = MailTransport::File { dir: dir.clone() };
+        let (tdb, state) = AppState::new_for_test_with(config).await?;
+        Ok((tdb, state, dir))
+    }
+
+    /// Token in the last mail to the email.
+    async fn mailed_token(dir: &Path, email: &str) -> anyhow::Result<String> {
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(&format!("{email}-")) {
+                names.push(name);
+            }
+        }
+        let name = names.iter().max().expect("mail should be sent");
+        let content = tokio::fs::read_to_string(dir.join(name)).await?;
+        let token = content.split("token=").nth(1).unwrap();
+        Ok(token.split_whitespace().next().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn verify_email_should_work() -> anyhow::Result<()> {
+        let (_tdb, state, dir) = new_state().await?;
+        sqlx::query("UPDATE workspaces SET require_verified_email = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let err = state.ensure_email_verified(1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::EmailNotVerified(_)));
+
+        let input = RequestEmail {
+            email: user.email.clone(),
+        };
+        state.resend_verification_email(&input).await?;
+        let token = mailed_token(&dir, &user.email).await?;
+
+        // the token is no access token, nor a token to reset the password
+        assert!(state.dk.verify(&token)?.scopes == vec![Scope::VerifyEmail]);
+        let input = ResetPassword {
+            token: token.clone(),
+            password: "654321".to_string(),
+        };
+        let err = state.reset_password(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::EmailTokenError(_)));
+
+        let input = VerifyEmail { token };
+        state.verify_email(&input).await?;
+        state.ensure_email_verified(1, 1).await?;
+        let err = state.verify_email(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::EmailTokenError(_)));
+
+        // verified already, unknown emails get no mail either
+        let mails = std::fs::read_dir(&dir)?.count();
+        for email in [user.email.as_str(), "nobody@acme.com"] {
+            let input = RequestEmail {
+                email: email.to_string(),
+            };
+            state.resend_verification_email(&input).await?;
+        }
+        assert_eq!(std::fs::read_dir(&dir)?.count(), mails);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_password_should_work() -> anyhow::Result<()> {
+        let (_tdb, state, dir) = new_state().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let (sid, _) = state.create_session(&user).await?;
+        let claims = TokenClaims::new(&user, sid);
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_some());
+
+        let input = RequestEmail {
+            email: user.email.clone(),
+        };
+        state.request_password_reset(&input).await?;
+        let first = mailed_token(&dir, &user.email).await?;
+        state.request_password_reset(&input).await?;
+        let token = mailed_token(&dir, &user.email).await?;
+        assert_ne!(first, token);
+
+        let input = ResetPassword {
+            token,
+            password: "654321".to_string(),
+        };
+        state.reset_password(&input).await?;
+        let err = state.reset_password(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::EmailTokenError(_)));
+        // the earlier token is void too
+        let input = ResetPassword {
+            token: first,
+            password: "hijacked".to_string(),
+        };
+        let err = state.reset_password(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::EmailTokenError(_)));
+
+        // signed out everywhere, the new password works
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_none());
+        let input = SigninUser {
+            email: user.email.clone(),
+            password: "123456".to_string(),
+        };
+        assert!(state.verify_user(&input).await?.is_none());
+        let input = SigninUser {
+            password: "654321".to_string(),
+            ..input
+        };
+        assert!(state.verify_user(&input).await?.is_some());
+        state.ensure_email_verified(1, 1).await?;
+        Ok(())
+    }
+}
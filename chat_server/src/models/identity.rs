@@ -0,0 +1,431 @@
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    models::{generate_token, mark_email_verified, CreateUser},
+    oidc::{OidcClient, OidcUser},
+    AppError, AppState,
+};
+
+/// The provider has to send the browser back within this many seconds.
+const OIDC_LOGIN_TIMEOUT: i64 = 60 * 10;
+
+/// Query of the redirect from the OIDC provider back to the server.
+#[derive(Debug, Clone, ToSchema, IntoParams, Serialize, Deserialize)]
+pub struct OidcCallback {
+    /// Authorization code to exchange for the id token
+    pub code: String,
+    /// State of the login, as sent to the provider
+    pub state: String,
+}
+
+/// A login started at the OIDC provider.
+#[derive(Debug, Clone)]
+pub struct OidcLogin {
+    /// Url of the provider to redirect the browser to
+    pub url: String,
+    /// State of the login, to keep in the browser until the provider sends it back
+    pub state: String,
+}
+
+impl AppState {
+    /// Start a login at the OIDC provider.
+    pub async fn start_oidc_login(&self) -> Result<OidcLogin, AppError> {
+        let oidc = self.oidc_client()?;
+        let (state, nonce, code_verifier) = (generate_token(), generate_token(), generate_token());
+
+        // logins abandoned at the provider are dropped with the next one
+        sqlx::query(
+            r#"
+            DELETE FROM oidc_logins
+            WHERE created_at < CURRENT_TIMESTAMP - $1 * INTERVAL '1 second'
+            "#,
+        )
+        .bind(OIDC_LOGIN_TIMEOUT as f64)
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO oidc_logins(state, nonce, code_verifier)
+            VALUES($1, $2, $3)
+            "#,
+        )
+        .bind(&state)
+        .bind(&nonce)
+        .bind(&code_verifier)
+        .execute(&self.pool)
+        .await?;
+
+        let url = oidc.authorize_url(&state, &nonce, &code_verifier).await?;
+        Ok(OidcLogin { url, state })
+    }
+
+    /// Finish the login the provider redirected back with, returns the user signed in to its
+    /// last used workspace. `browser_state` is the state kept by the browser that started the
+    /// login, so that nobody can slip the callback of their own login to someone else.
+    ///
+    /// An account of the provider signing in the first time is linked to the user with the same
+    /// email if the provider verified it, otherwise a user without password is created.
+    pub async fn finish_oidc_login(
+        &self,
+        input: &OidcCallback,
+        browser_state: Option<&str>,
+    ) -> Result<User, AppError> {
+        let oidc = self.oidc_client()?;
+        if browser_state != Some(input.state.as_str()) {
+            return Err(AppError::OidcError(
+                "Login was not started by this browser".to_string(),
+            ));
+        }
+        // each state can be used only once
+        let login: Option<(String, String)> = sqlx::query_as(
+            r#"
+            DELETE FROM oidc_logins
+            WHERE state = $1 AND created_at > CURRENT_TIMESTAMP - $2 * INTERVAL '1 second'
+            RETURNING nonce, code_verifier
+            "#,
+        )
+        .bind(&input.state)
+        .bind(OIDC_LOGIN_TIMEOUT as f64)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((nonce, code_verifier)) = login else {
+            return Err(AppError::OidcError(
+                "Invalid or expired login state".to_string(),
+            ));
+        };
+        let oidc_user = oidc.exchange(&input.code, &nonce, &code_verifier).await?;
+
+        let mut tx = self.pool.begin().await?;
+        let user_id = self.find_or_link_identity(&mut tx, &oidc_user).await?;
+        tx.commit().await?;
+
+        self.find_signin_user(user_id).await?.ok_or_else(|| {
+            AppError::PermissionDenied(format!(
+                "User {user_id} is not an active member of any workspace"
+            ))
+        })
+    }
+
+    async fn find_or_link_identity(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        oidc_user: &OidcUser,
+    ) -> Result<i64, AppError> {
+        let user_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT user_id
+            FROM user_identities
+            WHERE issuer = $1 AND subject = $2
+            "#,
+        )
+        .bind(&oidc_user.issuer)
+        .bind(&oidc_user.subject)
+        .fetch_optional(&mut **tx)
+        .await?;
+        if let Some(user_id) = user_id {
+            return Ok(user_id);
+        }
+
+        let user_id = match self.find_user_by_email(&oidc_user.email).await? {
+            // anyone can claim an unverified email at some providers
+            Some(_) if !oidc_user.email_verified => {
+                return Err(AppError::OidcError(format!(
+                    "Email {} is not verified by the provider, can't link it to the existing user",
+                    oidc_user.email
+                )));
+            }
+            Some(user) => user.id,
+            None => {
+                // the workspace claim comes from the provider, only the configured
+                // workspaces can be joined without an invite
+                let trusted = self
+                    .oidc_client()?
+                    .config
+                    .is_trusted_workspace(&oidc_user.workspace);
+                let input = CreateUser {
+                    email: oidc_user.email.clone(),
+                    fullname: oidc_user.fullname.clone(),
+                    workspace: oidc_user.workspace.clone(),
+                    password: String::new(),
+                    invite: None,
+                };
+                self.insert_user(tx, &input, None, trusted).await?.id
+            }
+        };
+        if oidc_user.email_verified {
+            mark_email_verified(tx, user_id).await?;
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO user_identities(issuer, subject, user_id)
+            VALUES($1, $2, $3)
+            "#,
+        )
+        .bind(&oidc_user.issuer)
+        .bind(&oidc_user.subject)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(user_id)
+    }
+
+    fn oidc_client(&self) -> Result<&OidcClient, AppError> {
+        self.oidc
+            .as_ref()
+            .ok_or_else(|| AppError::NotFound("OIDC login is not configured".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{extract::State, routing::get, routing::post, Form, Json, Router};
+    use jwt_simple::prelude::*;
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        config::{OidcClaims, OidcConfig},
+        models::SigninUser,
+        oidc::Jwk,
+        AppConfig,
+    };
+
+    const CLIENT_ID: &str = "chat";
+
+    /// Claims of the id token the provider issues for each code.
+    type Codes = Arc<Mutex<HashMap<String, Value>>>;
+
+    struct MockProvider {
+        issuer: String,
+        codes: Codes,
+    }
+
+    impl MockProvider {
+        async fn start() -> anyhow::Result<Self> {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let issuer = format!("http://{}", listener.local_addr()?);
+            let key = Arc::new(RS256KeyPair::generate(2048)?.with_key_id("test"));
+            let codes = Codes::default();
+
+            let discovery = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/authorize"),
+                "token_endpoint": format!("{issuer}/token"),
+                "jwks_uri": format!("{issuer}/jwks"),
+            });
+            let jwks = json!({ "keys": [Jwk::from_key(&key.public_key(), "test")?] });
+            let app = Router::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    get(|| async move { Json(discovery) }),
+                )
+                .route("/jwks", get(|| async move { Json(jwks) }))
+                .route("/token", post(token_handler))
+                .with_state((key, issuer.clone(), codes.clone()));
+            tokio::spawn(async move { axum::serve(listener, app).await });
+
+            Ok(Self { issuer, codes })
+        }
+
+        fn config(&self) -> OidcConfig {
+            OidcConfig {
+                issuer: self.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: "secret".to_string(),
+                redirect_url: "http://localhost:6688/api/oidc/callback".to_string(),
+                workspace: "sso".to_string(),
+                workspaces: vec![],
+                claims: OidcClaims::default(),
+            }
+        }
+
+        /// Sign in at the provider as the account with the claims, as the browser would.
+        fn authorize(&self, url: &str, claims: Value) -> anyhow::Result<OidcCallback> {
+            let url = reqwest::Url::parse(url)?;
+            assert!(url
+                .as_str()
+                .starts_with(&format!("{}/authorize", self.issuer)));
+            let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            assert_eq!(query["client_id"], CLIENT_ID);
+            assert_eq!(query["code_challenge_method"], "S256");
+
+            let mut claims = claims;
+            claims["nonce"] = query["nonce"].clone().into();
+            let code = generate_token();
+            self.codes.lock().unwrap().insert(code.clone(), claims);
+            Ok(OidcCallback {
+                code,
+                state: query["state"].clone(),
+            })
+        }
+    }
+
+    async fn token_handler(
+        State((key, issuer, codes)): State<(Arc<RS256KeyPair>, String, Codes)>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Json<Value> {
+        let mut custom = codes.lock().unwrap().remove(&form["code"]).unwrap();
+        let nonce = custom["nonce"].as_str().unwrap().to_string();
+        let subject = custom["sub"].as_str().unwrap().to_string();
+        custom.as_object_mut().unwrap().remove("sub");
+        custom.as_object_mut().unwrap().remove("nonce");
+
+        let claims = Claims::with_custom_claims(custom, Duration::from_mins(5))
+            .with_issuer(issuer)
+            .with_audience(CLIENT_ID)
+            .with_subject(subject)
+            .with_nonce(nonce);
+        let id_token = key.sign(claims).unwrap();
+        Json(json!({ "id_token": id_token, "token_type": "Bearer" }))
+    }
+
+    async fn new_state(
+        provider: &MockProvider,
+    ) -> anyhow::Result<(sqlx_db_tester::TestPg, AppState)> {
+        let mut config = AppConfig::load()?;
+        config.oidc = Some(provider.config());
+        Ok(AppState::new_for_test_with(config).await?)
+    }
+
+    /// Sign in at the provider as the account with the claims, in the same browser.
+    async fn login(
+        state: &AppState,
+        provider: &MockProvider,
+        claims: Value,
+    ) -> anyhow::Result<Result<User, AppError>> {
+        let login = state.start_oidc_login().await?;
+        let callback = provider.authorize(&login.url, claims)?;
+        Ok(state.finish_oidc_login(&callback, Some(&login.state)).await)
+    }
+
+    #[tokio::test]
+    async fn oidc_login_should_create_user() -> anyhow::Result<()> {
+        let provider = MockProvider::start().await?;
+        let (_tdb, state) = new_state(&provider).await?;
+
+        let claims = json!({
+            "sub": "alice", "email": "alice@sso.com", "email_verified": true, "name": "Alice",
+        });
+        let user = login(&state, &provider, claims.clone()).await??;
+        assert_eq!(user.email, "alice@sso.com");
+        assert_eq!(user.fullname, "Alice");
+        assert_eq!(user.ws_name, "sso");
+        let ws = state.find_workspace_by_id(user.ws_id as _).await?.unwrap();
+        assert_eq!(ws.owner_id, user.id);
+
+        // signing in again finds the same user, there's no password to sign in with
+        let again = login(&state, &provider, claims).await??;
+        assert_eq!(again.id, user.id);
+        let input = SigninUser {
+            email: "alice@sso.com".to_string(),
+            password: String::new(),
+        };
+        assert!(state.verify_user(&input).await?.is_none());
+
+        // others of the provider join the workspace without an invite
+        let claims = json!({ "sub": "bob", "email": "bob@sso.com", "name": "Bob" });
+        let bob = login(&state, &provider, claims).await??;
+        assert_eq!(bob.ws_id, user.ws_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oidc_login_should_require_invite_to_other_workspaces() -> anyhow::Result<()> {
+        let provider = MockProvider::start().await?;
+        let config = |workspaces: Vec<String>| -> anyhow::Result<AppConfig> {
+            let mut config = AppConfig::load()?;
+            config.oidc = Some(OidcConfig {
+                workspaces,
+                claims: OidcClaims {
+                    workspace: Some("org".to_string()),
+                    ..OidcClaims::default()
+                },
+                ..provider.config()
+            });
+            Ok(config)
+        };
+        let (_tdb, state) = AppState::new_for_test_with(config(vec![])?).await?;
+
+        // the claim can't name a workspace the provider is not trusted with
+        let eve = json!({ "sub": "eve", "email": "eve@sso.com", "org": "acme" });
+        let err = login(&state, &provider, eve.clone()).await?.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        assert!(state.find_user_by_email("eve@sso.com").await?.is_none());
+
+        // a new workspace is created by its first user, as with a signup
+        let claims = json!({ "sub": "carol", "email": "carol@sso.com", "org": "partner" });
+        let carol = login(&state, &provider, claims).await??;
+        assert_eq!(carol.ws_name, "partner");
+
+        // unless it is listed in the config
+        let (_tdb, state) = AppState::new_for_test_with(config(vec!["acme".to_string()])?).await?;
+        let user = login(&state, &provider, eve).await??;
+        assert_eq!(user.ws_name, "acme");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oidc_login_should_link_verified_email() -> anyhow::Result<()> {
+        let provider = MockProvider::start().await?;
+        let (_tdb, state) = new_state(&provider).await?;
+
+        // unverified emails can't take over the user
+        let claims = json!({ "sub": "hedon", "email": "hedon@acme.com", "email_verified": false });
+        let err = login(&state, &provider, claims).await?.unwrap_err();
+        assert!(matches!(err, AppError::OidcError(_)));
+
+        let claims = json!({ "sub": "hedon", "email": "hedon@acme.com", "email_verified": true });
+        let user = login(&state, &provider, claims).await??;
+        assert_eq!(user.id, 1);
+        assert_eq!(user.ws_name, "acme");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oidc_login_should_reject_invalid_state() -> anyhow::Result<()> {
+        let provider = MockProvider::start().await?;
+        let (_tdb, state) = new_state(&provider).await?;
+
+        let claims = json!({ "sub": "alice", "email": "alice@sso.com" });
+        let login = state.start_oidc_login().await?;
+        let mut callback = provider.authorize(&login.url, claims.clone())?;
+        callback.state = generate_token();
+        let err = state
+            .finish_oidc_login(&callback, Some(&callback.state))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::OidcError(_)));
+
+        // the callback of a login started in another browser, e.g. slipped in by an attacker
+        let attacker = state.start_oidc_login().await?;
+        let callback = provider.authorize(&attacker.url, claims.clone())?;
+        let victim = state.start_oidc_login().await?;
+        for browser_state in [None, Some(victim.state.as_str())] {
+            let err = state
+                .finish_oidc_login(&callback, browser_state)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, AppError::OidcError(_)));
+        }
+        // still fine in the browser that started it
+        state
+            .finish_oidc_login(&callback, Some(&attacker.state))
+            .await?;
+
+        // without a provider, there's no login
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let err = state.start_oidc_login().await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+}
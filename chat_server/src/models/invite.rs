@@ -0,0 +1,256 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, Transaction};
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+/// An invite to join a workspace, created by the owner of the workspace.
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Invite {
+    pub id: i64,
+    pub ws_id: i64,
+    /// Secret to sign up with, e.g. in an invite link
+    pub token: String,
+    pub email: Option<String>,
+    pub created_by: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub reusable: bool,
+    /// Users who signed up with the invite
+    pub uses: i32,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct CreateInvite {
+    /// Only this email can sign up with the invite if set
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Seconds until the invite expires, the default of the workspace if not set
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// Allow more than one signup with the invite
+    #[serde(default)]
+    pub reusable: bool,
+}
+
+impl AppState {
+    pub async fn create_invite(
+        &self,
+        input: CreateInvite,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Invite, AppError> {
+        let ws = self.verify_ws_owner(ws_id, user_id).await?;
+        if let Some(email) = &input.email {
+            if !email.contains('@') {
+                return Err(AppError::InviteError(format!("Invalid email {email}")));
+            }
+        }
+        if input.expires_in == Some(0) {
+            return Err(AppError::InviteError(
+                "Invite must expire in the future".to_string(),
+            ));
+        }
+
+        let invite = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_invites(ws_id, token, email, created_by, expires_at, reusable)
+            VALUES($1, $2, $3, $4, CURRENT_TIMESTAMP + $5 * INTERVAL '1 second', $6)
+            RETURNING id, ws_id, token, email, created_by, expires_at, reusable, uses, revoked_at,
+                created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(generate_token())
+        .bind(input.email)
+        .bind(user_id as i64)
+        .bind(
+            input
+                .expires_in
+                .map(|v| v as i64)
+                .or(ws.settings.invite_expires_in)
+                .map(|v| v as f64),
+        )
+        .bind(input.reusable)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(invite)
+    }
+
+    /// List the invites of the workspace, latest first.
+    pub async fn list_invites(&self, ws_id: u64, user_id: u64) -> Result<Vec<Invite>, AppError> {
+        self.verify_ws_owner(ws_id, user_id).await?;
+        let invites = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, token, email, created_by, expires_at, reusable, uses, revoked_at,
+                created_at
+            FROM workspace_invites
+            WHERE ws_id = $1
+            ORDER BY id DESC
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invites)
+    }
+
+    pub async fn revoke_invite(&self, id: u64, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        self.verify_ws_owner(ws_id, user_id).await?;
+        let ret = sqlx::query(
+            r#"
+            UPDATE workspace_invites
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND ws_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("invite id {id}")));
+        }
+
+        Ok(())
+    }
+
+    /// Use the invite to join its workspace, which must be `ws_id` if given, in the
+    /// transaction of the signup or the join. Returns the id of the workspace.
+    pub(crate) async fn redeem_invite(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        token: &str,
+        ws_id: Option<i64>,
+        email: &str,
+    ) -> Result<i64, AppError> {
+        let ws_id = sqlx::query_scalar(
+            r#"
+            UPDATE workspace_invites
+            SET uses = uses + 1
+            WHERE token = $1 AND ($2::bigint IS NULL OR ws_id = $2) AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+                AND (email IS NULL OR email = $3)
+                AND (reusable OR uses = 0)
+            RETURNING ws_id
+            "#,
+        )
+        .bind(token)
+        .bind(ws_id)
+        .bind(email)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        ws_id.ok_or_else(|| AppError::PermissionDenied("Invalid or expired invite".to_string()))
+    }
+}
+
+pub(crate) fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUser;
+
+    #[tokio::test]
+    async fn invite_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let invite = state.create_invite(CreateInvite::default(), 1, 1).await?;
+        assert_eq!(invite.token.len(), 64);
+        assert_eq!(invite.ws_id, 1);
+
+        // an existing workspace needs an invite
+        let mut input = CreateUser::new("acme", "Tom", "tom@acme.com", "123456");
+        let err = state.create_user(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        input.invite = Some(invite.token.clone());
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, 1);
+
+        // single use
+        let input = CreateUser {
+            invite: Some(invite.token),
+            ..CreateUser::new("acme", "Ann", "ann@acme.com", "123456")
+        };
+        let err = state.create_user(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let invites = state.list_invites(1, 1).await?;
+        assert_eq!(invites.len(), 1);
+        assert_eq!(invites[0].uses, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restricted_invite_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = CreateInvite {
+            email: Some("ann@acme.com".to_string()),
+            reusable: true,
+            ..Default::default()
+        };
+        let invite = state.create_invite(input, 1, 1).await?;
+
+        // other emails can't use it
+        let input = CreateUser {
+            invite: Some(invite.token.clone()),
+            ..CreateUser::new("acme", "Tom", "tom@acme.com", "123456")
+        };
+        let err = state.create_user(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // nor after being revoked
+        state.revoke_invite(invite.id as _, 1, 1).await?;
+        let input = CreateUser {
+            invite: Some(invite.token.clone()),
+            ..CreateUser::new("acme", "Ann", "ann@acme.com", "123456")
+        };
+        let err = state.create_user(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let err = state.revoke_invite(invite.id as _, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // expired
+        sqlx::query("UPDATE workspace_invites SET revoked_at = NULL, expires_at = now()")
+            .execute(&state.pool)
+            .await?;
+        let err = state.create_user(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_owner_should_manage_invites() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let err = state
+            .create_invite(CreateInvite::default(), 1, 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let err = state.list_invites(1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let input = CreateInvite {
+            expires_in: Some(0),
+            ..Default::default()
+        };
+        let err = state.create_invite(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::InviteError(_)));
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use std::str::FromStr;
 
 use chat_core::Message;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, Transaction};
 use utoipa::{IntoParams, ToSchema};
 
 use crate::{models::ChatFile, AppError, AppState};
@@ -11,16 +13,82 @@ pub struct CreateMessage {
     pub content: String,
     #[serde(default)]
     pub files: Vec<String>,
+    /// Reply to a message of the chat, the reply goes into the thread of that message
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct UpdateMessage {
+    pub content: String,
+    /// New files of the message, keep the current ones if not set
+    #[serde(default)]
+    pub files: Option<Vec<String>>,
+}
+
+/// A previous version of an edited message.
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct MessageEdit {
+    pub id: i64,
+    pub message_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize)]
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// Page through messages from an anchor, the latest messages if no anchor is set.
+/// At most one of the anchors can be set.
+#[derive(Debug, Clone, Default, IntoParams, ToSchema, Serialize, Deserialize)]
 pub struct ListMessages {
+    /// Messages older than the message id, `last_id` is also accepted
+    #[serde(default, alias = "last_id")]
+    pub before: Option<u64>,
+    /// Messages newer than the message id
+    #[serde(default)]
+    pub after: Option<u64>,
+    /// Messages on both sides of the message id, the message included
     #[serde(default)]
-    pub last_id: Option<u64>,
+    pub around: Option<u64>,
+    /// Messages sent before the time
+    #[serde(default)]
+    pub before_at: Option<DateTime<Utc>>,
+    /// Messages sent after the time
+    #[serde(default)]
+    pub after_at: Option<DateTime<Utc>>,
+    /// Messages on both sides of the time
+    #[serde(default)]
+    pub around_at: Option<DateTime<Utc>>,
+    /// Page size, 50 by default and 100 at most
     #[serde(default)]
     pub limit: u64,
 }
 
+/// A page of messages, latest first.
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct MessagePage {
+    pub items: Vec<Message>,
+    /// Pass as `before` to get older messages, not set if there are none
+    pub prev: Option<i64>,
+    /// Pass as `after` to get newer messages, not set if there are none
+    pub next: Option<i64>,
+    /// Whether there are more messages in the direction of the request
+    #[serde(alias = "hasMore")]
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PageAnchor {
+    Latest,
+    Before(i64),
+    After(i64),
+    Around(i64),
+}
+
 #[allow(dead_code)]
 impl AppState {
     pub async fn create_message(
@@ -29,35 +97,49 @@ impl AppState {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        // verify content should not be empty
-        if input.content.is_empty() {
-            return Err(AppError::CreateMessageError("Content is empty".to_string()));
-        }
+        self.verify_message_input(&input.content, &input.files, AppError::CreateMessageError)?;
 
-        // verify files exists
-        let base_dir = &self.config.server.base_dir;
-        for s in &input.files {
-            let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
-                return Err(AppError::CreateMessageError(format!(
-                    "File {} not exists",
-                    file.path(base_dir).display()
-                )));
+        // replies always belong to the thread of the top level message
+        let thread_root_id = match input.reply_to {
+            Some(reply_to) => {
+                let parent: Option<(Option<i64>,)> = sqlx::query_as(
+                    r#"
+                    SELECT thread_root_id
+                    FROM messages
+                    WHERE chat_id = $1 AND id = $2 AND deleted_at IS NULL
+                    "#,
+                )
+                .bind(chat_id as i64)
+                .bind(reply_to)
+                .fetch_optional(&self.pool)
+                .await?;
+                match parent {
+                    Some((root,)) => Some(root.unwrap_or(reply_to)),
+                    None => {
+                        return Err(AppError::CreateMessageError(format!(
+                            "Message {reply_to} to reply doesn't exist"
+                        )))
+                    }
+                }
             }
-        }
+            None => None,
+        };
 
         // create message
         let message: Message = sqlx::query_as(
             r#"
-            INSERT INTO messages(chat_id, sender_id, content, files)
-            VALUES($1, $2, $3, $4)
-            RETURNING id, chat_id, sender_id, content, files, created_at
+            INSERT INTO messages(chat_id, sender_id, content, files, reply_to, thread_root_id)
+            VALUES($1, $2, $3, $4, $5, $6)
+            RETURNING id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+                reply_to, thread_root_id
             "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(input.content)
         .bind(&input.files)
+        .bind(input.reply_to)
+        .bind(thread_root_id)
         .fetch_one(&self.pool)
         .await?;
 
@@ -68,30 +150,388 @@ impl AppState {
         &self,
         input: ListMessages,
         chat_id: u64,
-    ) -> Result<Vec<Message>, AppError> {
-        let last_id = input.last_id.unwrap_or(i64::MAX as _);
-        let limit = match input.limit {
-            0 => i64::MAX,
-            1..100 => input.limit as _,
-            _ => 100,
-        };
-        let messages: Vec<Message> = sqlx::query_as(
+    ) -> Result<MessagePage, AppError> {
+        // thread replies are listed in their thread only
+        self.list_message_page(input, chat_id, None).await
+    }
+
+    /// List the replies in the thread of a top level message, latest first.
+    pub async fn list_thread_messages(
+        &self,
+        input: ListMessages,
+        chat_id: u64,
+        root_id: u64,
+    ) -> Result<MessagePage, AppError> {
+        let root = sqlx::query(
             r#"
-            SELECT id, chat_id, sender_id, content, files, created_at
+            SELECT 1
             FROM messages
-            WHERE chat_id = $1 AND id < $2
-            ORDER BY id DESC
-            LIMIT $3
+            WHERE chat_id = $1 AND id = $2 AND thread_root_id IS NULL
             "#,
         )
         .bind(chat_id as i64)
-        .bind(last_id as i64)
-        .bind(limit)
+        .bind(root_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        if root.is_none() {
+            return Err(AppError::NotFound(format!(
+                "thread root message id {root_id}"
+            )));
+        }
+
+        self.list_message_page(input, chat_id, Some(root_id as i64))
+            .await
+    }
+
+    /// Edit the content of a message, the previous version is kept in the edit history.
+    pub async fn update_message(
+        &self,
+        input: UpdateMessage,
+        chat_id: u64,
+        msg_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let msg = self
+            .lock_sender_message(&mut tx, chat_id, msg_id, user_id)
+            .await?;
+        if msg.deleted_at.is_some() {
+            return Err(AppError::UpdateMessageError(format!(
+                "Message {msg_id} has been deleted"
+            )));
+        }
+
+        let files = input.files.unwrap_or(msg.files.clone());
+        self.verify_message_input(&input.content, &files, AppError::UpdateMessageError)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO message_edits(message_id, content, files)
+            VALUES($1, $2, $3)
+            "#,
+        )
+        .bind(msg.id)
+        .bind(&msg.content)
+        .bind(&msg.files)
+        .execute(&mut *tx)
+        .await?;
+
+        let message = sqlx::query_as(
+            r#"
+            UPDATE messages
+            SET content = $1, files = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $3
+            RETURNING id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+                reply_to, thread_root_id
+            "#,
+        )
+        .bind(input.content)
+        .bind(&files)
+        .bind(msg.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    /// Soft delete a message: it stays as a tombstone without content, and its edit history is dropped.
+    pub async fn delete_message(
+        &self,
+        chat_id: u64,
+        msg_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let msg = self
+            .lock_sender_message(&mut tx, chat_id, msg_id, user_id)
+            .await?;
+        if msg.deleted_at.is_some() {
+            return Ok(msg);
+        }
+
+        sqlx::query("DELETE FROM message_edits WHERE message_id = $1")
+            .bind(msg.id)
+            .execute(&mut *tx)
+            .await?;
+
+        let message = sqlx::query_as(
+            r#"
+            UPDATE messages
+            SET content = '', files = '{}', deleted_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            RETURNING id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+                reply_to, thread_root_id
+            "#,
+        )
+        .bind(msg.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    pub async fn list_message_edits(
+        &self,
+        chat_id: u64,
+        msg_id: u64,
+    ) -> Result<Vec<MessageEdit>, AppError> {
+        let edits = sqlx::query_as(
+            r#"
+            SELECT e.id, e.message_id, e.content, e.files, e.created_at
+            FROM message_edits e
+            JOIN messages m ON m.id = e.message_id
+            WHERE m.chat_id = $1 AND m.id = $2
+            ORDER BY e.id DESC
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(msg_id as i64)
         .fetch_all(&self.pool)
         .await?;
 
+        Ok(edits)
+    }
+
+    /// Messages are split at `pivot` into older ones (`id < pivot`) and newer ones (`id >= pivot`),
+    /// the page takes up to `limit` messages from the sides the anchor asks for. One more message is
+    /// fetched on each side to know whether there are more.
+    async fn list_message_page(
+        &self,
+        input: ListMessages,
+        chat_id: u64,
+        root_id: Option<i64>,
+    ) -> Result<MessagePage, AppError> {
+        let limit = input.page_size();
+        let anchor = self.page_anchor(&input, chat_id, root_id).await?;
+        let (pivot, older_len, newer_len) = match anchor {
+            PageAnchor::Latest => (i64::MAX, limit, 0),
+            PageAnchor::Before(id) => (id, limit, 0),
+            PageAnchor::After(id) => (id.saturating_add(1), 0, limit),
+            PageAnchor::Around(id) => (id, limit / 2, limit - limit / 2),
+        };
+
+        let mut older = self
+            .fetch_page_side(chat_id, root_id, pivot, false, older_len + 1)
+            .await?;
+        let has_older = older.len() as i64 > older_len;
+        older.truncate(older_len as _);
+
+        let (mut newer, has_newer) = if pivot == i64::MAX {
+            (vec![], false)
+        } else {
+            let mut newer = self
+                .fetch_page_side(chat_id, root_id, pivot, true, newer_len + 1)
+                .await?;
+            let has_newer = newer.len() as i64 > newer_len;
+            newer.truncate(newer_len as _);
+            (newer, has_newer)
+        };
+
+        newer.reverse();
+        let mut items = newer;
+        items.extend(older);
+        self.load_reactions(&mut items).await?;
+
+        let prev = has_older.then(|| items.last().map(|m| m.id).unwrap_or(pivot));
+        let next = has_newer.then(|| items.first().map(|m| m.id).unwrap_or(pivot - 1));
+        let has_more = match anchor {
+            PageAnchor::Latest | PageAnchor::Before(_) => has_older,
+            PageAnchor::After(_) => has_newer,
+            PageAnchor::Around(_) => has_older || has_newer,
+        };
+
+        Ok(MessagePage {
+            items,
+            prev,
+            next,
+            has_more,
+        })
+    }
+
+    /// Fetch messages older than the pivot latest first, or newer ones from the pivot oldest first.
+    async fn fetch_page_side(
+        &self,
+        chat_id: u64,
+        root_id: Option<i64>,
+        pivot: i64,
+        newer: bool,
+        limit: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        let (cond, order) = if newer {
+            ("m.id >= $3", "ASC")
+        } else {
+            ("m.id < $3", "DESC")
+        };
+        let sql = format!(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.content, m.files, m.created_at, m.updated_at,
+                m.deleted_at, m.reply_to, m.thread_root_id, t.reply_count, t.last_reply_at
+            FROM messages m
+            LEFT JOIN LATERAL (
+                SELECT count(*) AS reply_count, max(created_at) AS last_reply_at
+                FROM messages r
+                WHERE r.thread_root_id = m.id
+            ) t ON true
+            WHERE m.chat_id = $1 AND m.thread_root_id IS NOT DISTINCT FROM $2 AND {cond}
+            ORDER BY m.id {order}
+            LIMIT $4
+            "#
+        );
+        let messages = sqlx::query_as(&sql)
+            .bind(chat_id as i64)
+            .bind(root_id)
+            .bind(pivot)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
         Ok(messages)
     }
+
+    /// Resolve the anchor of the page, time anchors are resolved to the message ids around the time.
+    async fn page_anchor(
+        &self,
+        input: &ListMessages,
+        chat_id: u64,
+        root_id: Option<i64>,
+    ) -> Result<PageAnchor, AppError> {
+        let anchors = [
+            input.before.is_some(),
+            input.after.is_some(),
+            input.around.is_some(),
+            input.before_at.is_some(),
+            input.after_at.is_some(),
+            input.around_at.is_some(),
+        ];
+        if anchors.into_iter().filter(|set| *set).count() > 1 {
+            return Err(AppError::ListMessagesError(
+                "Only one of before, after, around, before_at, after_at and around_at can be set"
+                    .to_string(),
+            ));
+        }
+
+        let anchor = if let Some(id) = input.before {
+            PageAnchor::Before(id.min(i64::MAX as _) as _)
+        } else if let Some(id) = input.after {
+            PageAnchor::After(id.min(i64::MAX as _) as _)
+        } else if let Some(id) = input.around {
+            PageAnchor::Around(id.min(i64::MAX as _) as _)
+        } else if let Some(at) = input.before_at {
+            let id = self.first_message_since(chat_id, root_id, at).await?;
+            PageAnchor::Before(id.unwrap_or(i64::MAX))
+        } else if let Some(at) = input.after_at {
+            let id: Option<i64> = sqlx::query_scalar(
+                r#"
+                SELECT max(id)
+                FROM messages
+                WHERE chat_id = $1 AND thread_root_id IS NOT DISTINCT FROM $2 AND created_at <= $3
+                "#,
+            )
+            .bind(chat_id as i64)
+            .bind(root_id)
+            .bind(at)
+            .fetch_one(&self.pool)
+            .await?;
+            PageAnchor::After(id.unwrap_or(0))
+        } else if let Some(at) = input.around_at {
+            let id = self.first_message_since(chat_id, root_id, at).await?;
+            PageAnchor::Around(id.unwrap_or(i64::MAX))
+        } else {
+            PageAnchor::Latest
+        };
+
+        Ok(anchor)
+    }
+
+    async fn first_message_since(
+        &self,
+        chat_id: u64,
+        root_id: Option<i64>,
+        at: DateTime<Utc>,
+    ) -> Result<Option<i64>, AppError> {
+        let id = sqlx::query_scalar(
+            r#"
+            SELECT min(id)
+            FROM messages
+            WHERE chat_id = $1 AND thread_root_id IS NOT DISTINCT FROM $2 AND created_at >= $3
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(root_id)
+        .bind(at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Lock the message for update, only its sender can change it.
+    async fn lock_sender_message(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        chat_id: u64,
+        msg_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let msg: Option<Message> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+                reply_to, thread_root_id
+            FROM messages
+            WHERE chat_id = $1 AND id = $2
+            FOR UPDATE
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(msg_id as i64)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        match msg {
+            Some(msg) if msg.sender_id == user_id as i64 => Ok(msg),
+            Some(_) => Err(AppError::PermissionDenied(format!(
+                "User {user_id} is not the sender of message {msg_id}"
+            ))),
+            None => Err(AppError::NotFound(format!("message id {msg_id}"))),
+        }
+    }
+
+    fn verify_message_input(
+        &self,
+        content: &str,
+        files: &[String],
+        err: fn(String) -> AppError,
+    ) -> Result<(), AppError> {
+        // verify content should not be empty
+        if content.is_empty() {
+            return Err(err("Content is empty".to_string()));
+        }
+
+        // verify files exists
+        let base_dir = &self.config.server.base_dir;
+        for s in files {
+            let file = ChatFile::from_str(s)?;
+            if !file.path(base_dir).exists() {
+                return Err(err(format!(
+                    "File {} not exists",
+                    file.path(base_dir).display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ListMessages {
+    fn page_size(&self) -> i64 {
+        match self.limit {
+            0 => DEFAULT_PAGE_SIZE,
+            n => n.min(MAX_PAGE_SIZE as _) as _,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -104,6 +544,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![],
+            reply_to: None,
         };
         let message = state
             .create_message(input, 1, 1)
@@ -115,6 +556,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec!["invalid".to_string()],
+            reply_to: None,
         };
         let err = state.create_message(input, 1, 1).await.unwrap_err();
         assert_eq!(err.to_string(), "Invalid chat file path: invalid");
@@ -124,6 +566,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![url],
+            reply_to: None,
         };
         let message = state
             .create_message(input, 1, 1)
@@ -143,24 +586,249 @@ mod tests {
         Ok(file.url())
     }
 
+    #[tokio::test]
+    async fn thread_messages_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // reply to message 1, then reply to the reply
+        let input = CreateMessage {
+            content: "reply 1".to_string(),
+            files: vec![],
+            reply_to: Some(1),
+        };
+        let reply = state.create_message(input, 1, 2).await?;
+        assert_eq!(reply.reply_to, Some(1));
+        assert_eq!(reply.thread_root_id, Some(1));
+
+        let input = CreateMessage {
+            content: "reply 2".to_string(),
+            files: vec![],
+            reply_to: Some(reply.id),
+        };
+        let reply2 = state.create_message(input, 1, 3).await?;
+        assert_eq!(reply2.reply_to, Some(reply.id));
+        assert_eq!(reply2.thread_root_id, Some(1));
+
+        // replies are only listed in the thread
+        let input = ListMessages {
+            limit: 100,
+            ..Default::default()
+        };
+        let messages = state.list_messages(input.clone(), 1).await?.items;
+        assert_eq!(messages.len(), 10);
+        let root = messages.last().expect("root message should exist");
+        assert_eq!(root.id, 1);
+        assert_eq!(root.reply_count, 2);
+        assert_eq!(root.last_reply_at, Some(reply2.created_at));
+
+        let thread = state.list_thread_messages(input.clone(), 1, 1).await?.items;
+        assert_eq!(thread.len(), 2);
+        assert_eq!(thread[0].id, reply2.id);
+
+        // reply is not a thread root
+        let err = state
+            .list_thread_messages(input, 1, reply.id as _)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // reply to a message in another chat should fail
+        let input = CreateMessage {
+            content: "reply".to_string(),
+            files: vec![],
+            reply_to: Some(1),
+        };
+        let err = state.create_message(input, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Message 1 to reply doesn't exist"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_message_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // message 1 is sent by user 1 in chat 1
+        let input = UpdateMessage {
+            content: "hello again".to_string(),
+            files: None,
+        };
+        let msg = state.update_message(input, 1, 1, 1).await?;
+        assert_eq!(msg.content, "hello again");
+        assert!(msg.updated_at.is_some());
+
+        let edits = state.list_message_edits(1, 1).await?;
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].content, "Hello, world!");
+
+        // only sender can edit
+        let input = UpdateMessage {
+            content: "hacked".to_string(),
+            files: None,
+        };
+        let err = state.update_message(input, 1, 1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // message must belong to the chat
+        let input = UpdateMessage {
+            content: "hello".to_string(),
+            files: None,
+        };
+        let err = state.update_message(input, 2, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_message_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = UpdateMessage {
+            content: "hello again".to_string(),
+            files: None,
+        };
+        state.update_message(input, 1, 1, 1).await?;
+
+        let err = state.delete_message(1, 1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let msg = state.delete_message(1, 1, 1).await?;
+        assert!(msg.deleted_at.is_some());
+        assert_eq!(msg.content, "");
+        assert!(state.list_message_edits(1, 1).await?.is_empty());
+
+        // deleted message stays as a tombstone
+        let input = ListMessages {
+            limit: 100,
+            ..Default::default()
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages.len(), 10);
+
+        // tombstone can't be edited
+        let input = UpdateMessage {
+            content: "hello".to_string(),
+            files: None,
+        };
+        let err = state.update_message(input, 1, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "update message error: Message 1 has been deleted"
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_messages_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = ListMessages {
-            last_id: None,
             limit: 6,
+            ..Default::default()
         };
-        let messages = state.list_messages(input, 1).await?;
-        assert_eq!(messages.len(), 6);
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(page.items.len(), 6);
+        assert_eq!(page.items[0].id, 10);
+        assert_eq!(page.prev, Some(5));
+        assert_eq!(page.next, None);
+        assert!(page.has_more);
 
-        let last_id = messages.last().expect("last message should exist").id;
         let input = ListMessages {
-            last_id: Some(last_id as _),
+            before: page.prev.map(|id| id as _),
             limit: 6,
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(page.items.len(), 10 - 6);
+        assert_eq!(page.prev, None);
+        assert_eq!(page.next, Some(4));
+        assert!(!page.has_more);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_after_and_around_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ListMessages {
+            after: Some(3),
+            limit: 4,
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        let ids: Vec<_> = page.items.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![7, 6, 5, 4]);
+        assert_eq!(page.prev, Some(4));
+        assert_eq!(page.next, Some(7));
+        assert!(page.has_more);
+
+        let input = ListMessages {
+            around: Some(5),
+            limit: 4,
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        let ids: Vec<_> = page.items.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![6, 5, 4, 3]);
+        assert_eq!(page.prev, Some(3));
+        assert_eq!(page.next, Some(6));
+
+        // nothing after the latest message
+        let input = ListMessages {
+            after: Some(10),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert!(page.items.is_empty());
+        // message 10 itself is before 11
+        assert_eq!(page.prev, Some(11));
+        assert!(!page.has_more);
+
+        // time anchors resolve to the messages around the time
+        let input = ListMessages {
+            after_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert!(page.items.is_empty());
+        let input = ListMessages {
+            before_at: Some(Utc::now()),
+            ..Default::default()
         };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(page.items.len(), 10);
 
-        let messages = state.list_messages(input, 1).await?;
-        assert_eq!(messages.len(), 10 - 6);
+        let input = ListMessages {
+            before: Some(5),
+            after: Some(1),
+            ..Default::default()
+        };
+        let err = state.list_messages(input, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::ListMessagesError(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_cap_limit() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for i in 0..120 {
+            let input = CreateMessage {
+                content: format!("message {i}"),
+                files: vec![],
+                reply_to: None,
+            };
+            state.create_message(input, 2, 1).await?;
+        }
+
+        let page = state.list_messages(ListMessages::default(), 2).await?;
+        assert_eq!(page.items.len(), 50);
+        let input = ListMessages {
+            limit: 1000,
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 2).await?;
+        assert_eq!(page.items.len(), 100);
+        assert!(page.has_more);
         Ok(())
     }
 }
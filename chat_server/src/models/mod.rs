@@ -1,13 +1,30 @@
 mod chat;
+mod email;
 mod file;
+mod identity;
+mod invite;
 mod message;
+mod reaction;
+mod receipt;
+mod search;
+mod session;
+mod two_factor;
 mod user;
 mod workspace;
 
 pub use chat::*;
+pub use email::*;
+pub use identity::*;
+pub use invite::*;
 pub use message::*;
+pub use reaction::*;
+pub use receipt::*;
+pub use search::*;
 use serde::{Deserialize, Serialize};
+pub use session::*;
+pub use two_factor::*;
 pub use user::*;
+pub use workspace::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatFile {
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use chat_core::{Message, Reaction};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+const MAX_EMOJI_LEN: usize = 64;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct CreateReaction {
+    /// Emoji of the reaction, e.g. `👍` or `:thumbsup:`
+    pub emoji: String,
+}
+
+impl AppState {
+    pub async fn add_reaction(
+        &self,
+        input: CreateReaction,
+        chat_id: u64,
+        msg_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Reaction>, AppError> {
+        if input.emoji.is_empty() || input.emoji.chars().count() > MAX_EMOJI_LEN {
+            return Err(AppError::ReactionError(format!(
+                "Emoji must be 1 to {MAX_EMOJI_LEN} characters"
+            )));
+        }
+
+        let msg = sqlx::query(
+            r#"
+            SELECT 1
+            FROM messages
+            WHERE chat_id = $1 AND id = $2 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(msg_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        if msg.is_none() {
+            return Err(AppError::NotFound(format!("message id {msg_id}")));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO message_reactions(message_id, user_id, emoji)
+            VALUES($1, $2, $3)
+            ON CONFLICT (message_id, user_id, emoji) DO NOTHING
+            "#,
+        )
+        .bind(msg_id as i64)
+        .bind(user_id as i64)
+        .bind(&input.emoji)
+        .execute(&self.pool)
+        .await?;
+
+        self.list_reactions(msg_id).await
+    }
+
+    pub async fn remove_reaction(
+        &self,
+        chat_id: u64,
+        msg_id: u64,
+        user_id: u64,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM message_reactions r
+            USING messages m
+            WHERE m.id = r.message_id AND m.chat_id = $1
+                AND r.message_id = $2 AND r.user_id = $3 AND r.emoji = $4
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(msg_id as i64)
+        .bind(user_id as i64)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "reaction {emoji} on message id {msg_id}"
+            )));
+        }
+
+        self.list_reactions(msg_id).await
+    }
+
+    pub async fn list_reactions(&self, msg_id: u64) -> Result<Vec<Reaction>, AppError> {
+        let reactions = sqlx::query_as(
+            r#"
+            SELECT emoji, count(*) AS count, array_agg(user_id ORDER BY created_at) AS user_ids
+            FROM message_reactions
+            WHERE message_id = $1
+            GROUP BY emoji
+            ORDER BY min(created_at)
+            "#,
+        )
+        .bind(msg_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(reactions)
+    }
+
+    /// Load the aggregated reactions of the messages in one query.
+    pub(crate) async fn load_reactions(&self, messages: &mut [Message]) -> Result<(), AppError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+        let rows: Vec<(i64, String, i64, Vec<i64>)> = sqlx::query_as(
+            r#"
+            SELECT message_id, emoji, count(*), array_agg(user_id ORDER BY created_at)
+            FROM message_reactions
+            WHERE message_id = ANY($1)
+            GROUP BY message_id, emoji
+            ORDER BY min(created_at)
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reactions: HashMap<i64, Vec<Reaction>> = HashMap::new();
+        for (message_id, emoji, count, user_ids) in rows {
+            reactions.entry(message_id).or_default().push(Reaction {
+                emoji,
+                count,
+                user_ids,
+            });
+        }
+        for msg in messages {
+            msg.reactions = reactions.remove(&msg.id).unwrap_or_default();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ListMessages;
+
+    #[tokio::test]
+    async fn add_and_remove_reaction_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let thumbs_up = CreateReaction {
+            emoji: "👍".to_string(),
+        };
+        state.add_reaction(thumbs_up.clone(), 1, 1, 1).await?;
+        state.add_reaction(thumbs_up.clone(), 1, 1, 2).await?;
+        // adding the same reaction twice is a no-op
+        state.add_reaction(thumbs_up.clone(), 1, 1, 2).await?;
+        let reactions = state
+            .add_reaction(
+                CreateReaction {
+                    emoji: "🎉".to_string(),
+                },
+                1,
+                1,
+                3,
+            )
+            .await?;
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].emoji, "👍");
+        assert_eq!(reactions[0].count, 2);
+        assert_eq!(reactions[0].user_ids, vec![1, 2]);
+        assert_eq!(reactions[1].emoji, "🎉");
+        assert_eq!(reactions[1].count, 1);
+
+        let reactions = state.remove_reaction(1, 1, 1, "👍").await?;
+        assert_eq!(reactions[0].count, 1);
+        assert_eq!(reactions[0].user_ids, vec![2]);
+
+        let err = state.remove_reaction(1, 1, 1, "👍").await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // message must belong to the chat
+        let err = state.add_reaction(thumbs_up, 2, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_include_reactions() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = CreateReaction {
+            emoji: "👍".to_string(),
+        };
+        state.add_reaction(input, 1, 10, 2).await?;
+
+        let input = ListMessages {
+            limit: 2,
+            ..Default::default()
+        };
+        let messages = state.list_messages(input, 1).await?.items;
+        assert_eq!(messages[0].id, 10);
+        assert_eq!(messages[0].reactions.len(), 1);
+        assert_eq!(messages[0].reactions[0].count, 1);
+        assert!(messages[1].reactions.is_empty());
+        Ok(())
+    }
+}
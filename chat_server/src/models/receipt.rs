@@ -0,0 +1,143 @@
+use chat_core::ReadReceipt;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct MarkRead {
+    /// The last message read, the latest message of the chat if not set
+    #[serde(default)]
+    pub message_id: Option<i64>,
+}
+
+impl AppState {
+    /// Mark messages of the chat as read up to a message, the receipt never moves backwards.
+    pub async fn mark_read(
+        &self,
+        input: MarkRead,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ReadReceipt, AppError> {
+        let last_read_id: Option<i64> = match input.message_id {
+            Some(id) => {
+                sqlx::query_scalar(
+                    r#"
+                SELECT id
+                FROM messages
+                WHERE chat_id = $1 AND id = $2
+                "#,
+                )
+                .bind(chat_id as i64)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?
+            }
+            None => {
+                sqlx::query_scalar(
+                    r#"
+                SELECT max(id)
+                FROM messages
+                WHERE chat_id = $1
+                "#,
+                )
+                .bind(chat_id as i64)
+                .fetch_one(&self.pool)
+                .await?
+            }
+        };
+        let Some(last_read_id) = last_read_id else {
+            return Err(AppError::NotFound(format!("message in chat id {chat_id}")));
+        };
+
+        let receipt =
+            ReadReceipt::upsert(&self.pool, chat_id as i64, user_id as i64, last_read_id).await?;
+
+        match receipt {
+            Some(receipt) => Ok(receipt),
+            // already read further, keep the current receipt
+            None => {
+                let receipt = sqlx::query_as(
+                    r#"
+                    SELECT chat_id, user_id, last_read_id, updated_at
+                    FROM read_receipts
+                    WHERE chat_id = $1 AND user_id = $2
+                    "#,
+                )
+                .bind(chat_id as i64)
+                .bind(user_id as i64)
+                .fetch_one(&self.pool)
+                .await?;
+                Ok(receipt)
+            }
+        }
+    }
+
+    pub async fn fetch_read_receipts(&self, chat_id: u64) -> Result<Vec<ReadReceipt>, AppError> {
+        let receipts = sqlx::query_as(
+            r#"
+            SELECT chat_id, user_id, last_read_id, updated_at
+            FROM read_receipts
+            WHERE chat_id = $1
+            ORDER BY user_id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(receipts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mark_read_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // chat 1 has 10 messages, user 2 sent 2 of them
+        let chats = state.fetch_chats(2, 1).await?;
+        let chat = chats.iter().find(|c| c.id == 1).unwrap();
+        assert_eq!(chat.unread_count, 8);
+
+        let input = MarkRead {
+            message_id: Some(5),
+        };
+        let receipt = state.mark_read(input, 1, 2).await?;
+        assert_eq!(receipt.last_read_id, 5);
+
+        let chats = state.fetch_chats(2, 1).await?;
+        let chat = chats.iter().find(|c| c.id == 1).unwrap();
+        assert_eq!(chat.unread_count, 4);
+
+        // read to the latest message
+        let receipt = state.mark_read(MarkRead::default(), 1, 2).await?;
+        assert_eq!(receipt.last_read_id, 10);
+
+        // receipt never moves backwards
+        let input = MarkRead {
+            message_id: Some(3),
+        };
+        let receipt = state.mark_read(input, 1, 2).await?;
+        assert_eq!(receipt.last_read_id, 10);
+
+        let chats = state.fetch_chats(2, 1).await?;
+        let chat = chats.iter().find(|c| c.id == 1).unwrap();
+        assert_eq!(chat.unread_count, 0);
+
+        let receipts = state.fetch_read_receipts(1).await?;
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].user_id, 2);
+
+        // message must belong to the chat
+        let input = MarkRead {
+            message_id: Some(1),
+        };
+        let err = state.mark_read(input, 2, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+}
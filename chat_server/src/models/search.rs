@@ -0,0 +1,203 @@
+use chat_core::{ChatType, ChatUser, Message, Presence};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, IntoParams, ToSchema, Serialize, Deserialize)]
+pub struct SearchMessages {
+    /// Search query, supports `"quoted phrases"`, `or` and `-excluded` words
+    pub q: String,
+    #[serde(default)]
+    pub last_id: Option<u64>,
+    #[serde(default)]
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct SearchChat {
+    pub id: i64,
+    pub name: Option<String>,
+    pub r#type: ChatType,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct SearchResult {
+    pub message: Message,
+    pub chat: SearchChat,
+    pub sender: ChatUser,
+    /// Matched fragments of the content as HTML: the content is escaped and the matches are
+    /// wrapped in `<mark></mark>`.
+    pub snippet: String,
+}
+
+#[derive(Debug, FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    message: Message,
+    snippet: String,
+    chat_name: Option<String>,
+    chat_type: ChatType,
+    fullname: String,
+    email: String,
+    presence: Presence,
+}
+
+impl AppState {
+    /// Search messages in all chats of the workspace the user is a member of, latest first.
+    pub async fn search_messages(
+        &self,
+        input: SearchMessages,
+        user_id: u64,
+        ws_id: u64,
+    ) -> Result<Vec<SearchResult>, AppError> {
+        let q = input.q.trim();
+        if q.is_empty() {
+            return Err(AppError::SearchError("Query is empty".to_string()));
+        }
+
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let limit = match input.limit {
+            0 => 20,
+            1..100 => input.limit as _,
+            _ => 100,
+        };
+        let rows: Vec<SearchRow> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.content, m.files, m.created_at, m.updated_at,
+                m.deleted_at, m.reply_to, m.thread_root_id,
+                ts_headline('simple', replace(replace(replace(replace(replace(m.content,
+                    '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;'), q,
+                    'StartSel=<mark>, StopSel=</mark>, MaxWords=20, MinWords=5') AS snippet,
+                c.name AS chat_name, c.type AS chat_type, u.fullname, u.email,
+                COALESCE(p.status, 'offline') AS presence
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            JOIN users u ON u.id = m.sender_id
+            LEFT JOIN user_presence p ON p.user_id = u.id,
+            websearch_to_tsquery('simple', $1) q
+            WHERE c.ws_id = $2 AND $3 = ANY(c.members) AND m.deleted_at IS NULL
+                AND m.content_tsv @@ q AND m.id < $4
+            ORDER BY m.id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(q)
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(last_id as i64)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let results = rows
+            .into_iter()
+            .map(|row| SearchResult {
+                chat: SearchChat {
+                    id: row.message.chat_id,
+                    name: row.chat_name,
+                    r#type: row.chat_type,
+                },
+                sender: ChatUser {
+                    id: row.message.sender_id,
+                    fullname: row.fullname,
+                    email: row.email,
+                    presence: row.presence,
+                },
+                snippet: row.snippet,
+                message: row.message,
+            })
+            .collect();
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateMessage;
+
+    #[tokio::test]
+    async fn search_messages_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // chat 1 has "Hello, world!" 4 times
+        let input = SearchMessages {
+            q: "hello".to_string(),
+            last_id: None,
+            limit: 3,
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].message.id, 10);
+        assert_eq!(results[0].chat.name.as_deref(), Some("general"));
+        assert_eq!(results[0].sender.fullname, "Hedon");
+        assert_eq!(results[0].snippet, "<mark>Hello</mark>, world!");
+
+        let input = SearchMessages {
+            q: "hello".to_string(),
+            last_id: Some(results[2].message.id as _),
+            limit: 3,
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.id, 1);
+
+        // only the marks are html, the content is escaped
+        let input = CreateMessage {
+            content: r#"<b onclick="alert()">ciao</b> & 'bye'"#.to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        state.create_message(input, 1, 1).await?;
+        let input = SearchMessages {
+            q: "ciao".to_string(),
+            last_id: None,
+            limit: 10,
+        };
+        let results = state.search_messages(input, 1, 1).await?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].snippet,
+            "<mark>ciao</mark>&lt;/b&gt; &amp; &#39;bye&#39;"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_messages_should_be_scoped_to_member_chats() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // user 4 is not a member of chat 2
+        let input = CreateMessage {
+            content: "secret plan".to_string(),
+            files: vec![],
+            reply_to: None,
+        };
+        state.create_message(input, 2, 1).await?;
+
+        let input = SearchMessages {
+            q: "secret".to_string(),
+            last_id: None,
+            limit: 10,
+        };
+        let results = state.search_messages(input.clone(), 1, 1).await?;
+        assert_eq!(results.len(), 1);
+        let results = state.search_messages(input.clone(), 4, 1).await?;
+        assert!(results.is_empty());
+        // other workspace
+        let results = state.search_messages(input, 1, 2).await?;
+        assert!(results.is_empty());
+
+        let input = SearchMessages {
+            q: " ".to_string(),
+            last_id: None,
+            limit: 10,
+        };
+        let err = state.search_messages(input, 1, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "search error: Query is empty");
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Postgres, Transaction};
+use utoipa::ToSchema;
+
+use crate::{models::generate_token, AppError, AppState};
+
+/// A session ends if it isn't refreshed for this long.
+const SESSION_DURATION: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct RefreshSession {
+    /// Refresh token of the session, it can be used only once
+    pub refresh_token: String,
+}
+
+impl AppState {
+    /// Start a session of the user in its current workspace, returns the session id and the
+    /// first refresh token.
+    pub async fn create_session(&self, user: &User) -> Result<(i64, String), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let sid: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO sessions(user_id, ws_id, expires_at)
+            VALUES($1, $2, CURRENT_TIMESTAMP + $3 * INTERVAL '1 second')
+            RETURNING id
+            "#,
+        )
+        .bind(user.id)
+        .bind(user.ws_id)
+        .bind(SESSION_DURATION as f64)
+        .fetch_one(&mut *tx)
+        .await?;
+        let refresh_token = insert_refresh_token(&mut tx, sid).await?;
+        tx.commit().await?;
+
+        Ok((sid, refresh_token))
+    }
+
+    /// Rotate the refresh token of the session and extend it. Returns the user scoped to the
+    /// workspace of the session, the session id and the next refresh token.
+    ///
+    /// A refresh token used twice means it leaked, the session is revoked then. A user who
+    /// doesn't meet the requirements of the workspace anymore keeps the refresh token unused.
+    pub async fn refresh_session(
+        &self,
+        refresh_token: &str,
+    ) -> Result<(User, i64, String), AppError> {
+        let token_hash = hash_token(refresh_token);
+        let mut tx = self.pool.begin().await?;
+        let sid: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE refresh_tokens
+            SET used_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND used_at IS NULL
+            RETURNING session_id
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(sid) = sid else {
+            let sid: Option<i64> =
+                sqlx::query_scalar("SELECT session_id FROM refresh_tokens WHERE token_hash = $1")
+                    .bind(&token_hash)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+            let Some(sid) = sid else {
+                return Err(AppError::InvalidSession(
+                    "Invalid refresh token".to_string(),
+                ));
+            };
+            revoke(&mut tx, sid).await?;
+            tx.commit().await?;
+            self.sessions.remove(sid);
+            return Err(AppError::InvalidSession(
+                "Refresh token used already, the session is revoked".to_string(),
+            ));
+        };
+
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            UPDATE sessions s
+            SET expires_at = CURRENT_TIMESTAMP + $2 * INTERVAL '1 second'
+            FROM users u, workspaces w, workspace_members m
+            WHERE s.id = $1 AND s.revoked_at IS NULL AND s.expires_at > CURRENT_TIMESTAMP
+                AND u.id = s.user_id AND w.id = s.ws_id
+                AND m.ws_id = s.ws_id AND m.user_id = s.user_id AND m.deactivated_at IS NULL
+            RETURNING u.id, s.ws_id, w.name AS ws_name, u.fullname, u.email, u.created_at
+            "#,
+        )
+        .bind(sid)
+        .bind(SESSION_DURATION as f64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user) = user else {
+            return Err(AppError::InvalidSession(
+                "Session expired or revoked".to_string(),
+            ));
+        };
+        // dropping the transaction on an error rolls back the use of the token
+        self.ensure_email_verified(user.id, user.ws_id).await?;
+        self.ensure_two_factor(user.id, user.ws_id).await?;
+        let refresh_token = insert_refresh_token(&mut tx, sid).await?;
+        tx.commit().await?;
+
+        Ok((user, sid, refresh_token))
+    }
+
+    /// Sign the session of the user out, its tokens can't be used anymore.
+    pub async fn revoke_session(&self, sid: i64, user_id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE sessions
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(sid)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        self.sessions.remove(sid);
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("session id {sid}")));
+        }
+
+        Ok(())
+    }
+}
+
+async fn insert_refresh_token(
+    tx: &mut Transaction<'_, Postgres>,
+    sid: i64,
+) -> Result<String, AppError> {
+    let token = generate_token();
+    sqlx::query("INSERT INTO refresh_tokens(token_hash, session_id) VALUES($1, $2)")
+        .bind(hash_token(&token))
+        .bind(sid)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(token)
+}
+
+async fn revoke(tx: &mut Transaction<'_, Postgres>, sid: i64) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE sessions SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP) WHERE id = $1",
+    )
+    .bind(sid)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Revoke the sessions of the user in the workspace, returns their ids to drop them from the
+/// session cache once committed.
+pub(crate) async fn revoke_workspace_sessions(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    ws_id: i64,
+) -> Result<Vec<i64>, AppError> {
+    let sids = sqlx::query_scalar(
+        r#"
+        UPDATE sessions
+        SET revoked_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND ws_id = $2 AND revoked_at IS NULL
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(ws_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(sids)
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::TokenClaims;
+
+    #[tokio::test]
+    async fn refresh_session_should_rotate_tokens() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let (sid, refresh_token) = state.create_session(&user).await?;
+
+        let (user, new_sid, new_refresh_token) = state.refresh_session(&refresh_token).await?;
+        assert_eq!(new_sid, sid);
+        assert_eq!(user.id, 1);
+        assert_eq!(user.ws_name, "acme");
+        assert_ne!(new_refresh_token, refresh_token);
+
+        let err = state.refresh_session("invalid").await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidSession(_)));
+
+        // replaying the used token revokes the session, the new token is useless too
+        let claims = TokenClaims::new(&user, sid);
+        assert_eq!(state.sessions.load(&claims, &state.pool).await?, Some(user));
+        let err = state.refresh_session(&refresh_token).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidSession(_)));
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_none());
+        let err = state.refresh_session(&new_refresh_token).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidSession(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_session_should_keep_token_if_requirements_fail() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let (_, refresh_token) = state.create_session(&user).await?;
+
+        sqlx::query("UPDATE workspaces SET require_two_factor = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let err = state.refresh_session(&refresh_token).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorRequired(_)));
+        sqlx::query(
+            "UPDATE workspaces SET require_two_factor = FALSE, require_verified_email = TRUE",
+        )
+        .execute(&state.pool)
+        .await?;
+        let err = state.refresh_session(&refresh_token).await.unwrap_err();
+        assert!(matches!(err, AppError::EmailNotVerified(_)));
+
+        // the token wasn't used up, so it isn't a replay
+        sqlx::query("UPDATE workspaces SET require_verified_email = FALSE")
+            .execute(&state.pool)
+            .await?;
+        let (user, _, _) = state.refresh_session(&refresh_token).await?;
+        assert_eq!(user.id, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoke_session_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let (sid, refresh_token) = state.create_session(&user).await?;
+        let claims = TokenClaims::new(&user, sid);
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_some());
+
+        // only the user of the session can sign it out
+        let err = state.revoke_session(sid, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        state.revoke_session(sid, 1).await?;
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_none());
+        let err = state.refresh_session(&refresh_token).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidSession(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,619 @@
+use anyhow::Context;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{Scope, TokenClaims, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, Transaction};
+use totp_rs::{Algorithm, TOTP};
+use utoipa::ToSchema;
+
+use crate::{
+    models::{hash_password, verify_password},
+    AppError, AppState,
+};
+
+const TOTP_ISSUER: &str = "Chat";
+const TOTP_DIGITS: usize = 6;
+/// Seconds of a time step, codes of the steps next to the current one are accepted too.
+const TOTP_STEP: u64 = 30;
+/// Seconds to enter the code after signing in with the password.
+const CHALLENGE_DURATION: u64 = 60 * 5;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// Base32 secret, to enter it in the authenticator by hand
+    pub secret: String,
+    /// `otpauth://` uri, usually shown as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct TotpCode {
+    /// Code of the authenticator, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    /// One-time codes to use when the authenticator is lost, they are shown only once
+    pub recovery_codes: Vec<String>,
+}
+
+/// Signin waiting for the second factor.
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct TwoFactorChallenge {
+    /// Token to finish the signin with, along with the code
+    pub challenge_token: String,
+    /// Seconds until the challenge expires
+    pub expires_in: u64,
+    /// Set if the workspace requires two-factor authentication and the user has to enroll the
+    /// authenticator first, the code of the new authenticator enables it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otpauth_uri: Option<String>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct TwoFactorSignin {
+    pub challenge_token: String,
+    /// Code of the authenticator, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, FromRow)]
+struct TotpState {
+    email: String,
+    totp_secret: Option<String>,
+    totp_enabled_at: Option<DateTime<Utc>>,
+    totp_last_step: i64,
+}
+
+impl AppState {
+    /// Generate a new secret for the user, two-factor authentication is enabled once the user
+    /// enters a code of it.
+    pub async fn enroll_totp(&self, user_id: i64) -> Result<TotpEnrollment, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let state = lock_totp_state(&mut tx, user_id).await?;
+        if state.totp_enabled_at.is_some() {
+            return Err(AppError::PermissionDenied(
+                "Two-factor authentication is enabled already, disable it first".to_string(),
+            ));
+        }
+        let enrollment = enroll(&mut tx, user_id, &state.email).await?;
+        tx.commit().await?;
+
+        Ok(enrollment)
+    }
+
+    /// Enable two-factor authentication with a code of the enrolled secret, returns the
+    /// recovery codes.
+    pub async fn enable_totp(
+        &self,
+        user_id: i64,
+        input: &TotpCode,
+    ) -> Result<RecoveryCodes, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let state = lock_totp_state(&mut tx, user_id).await?;
+        if state.totp_enabled_at.is_some() || state.totp_secret.is_none() {
+            return Err(AppError::PermissionDenied(
+                "Enroll a new authenticator first".to_string(),
+            ));
+        }
+        if !verify_totp(&mut tx, user_id, &state, &input.code).await? {
+            return Err(AppError::TwoFactorError("Invalid code".to_string()));
+        }
+        let codes = enable(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        Ok(codes)
+    }
+
+    /// Disable two-factor authentication, unless a workspace of the user requires it.
+    pub async fn disable_totp(&self, user_id: i64, input: &TotpCode) -> Result<(), AppError> {
+        let required: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM workspace_members m
+                JOIN workspaces w ON w.id = m.ws_id
+                WHERE m.user_id = $1 AND m.deactivated_at IS NULL AND w.require_two_factor
+            )
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        if required {
+            return Err(AppError::TwoFactorRequired(
+                "A workspace of the user requires two-factor authentication".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        self.verify_second_factor(&mut tx, user_id, &input.code)
+            .await?;
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET totp_secret = NULL, totp_enabled_at = NULL
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Replace the recovery codes of the user, e.g. when few of them are left.
+    pub async fn regenerate_recovery_codes(
+        &self,
+        user_id: i64,
+        input: &TotpCode,
+    ) -> Result<RecoveryCodes, AppError> {
+        let mut tx = self.pool.begin().await?;
+        self.verify_second_factor(&mut tx, user_id, &input.code)
+            .await?;
+        let codes = insert_recovery_codes(&mut tx, user_id).await?;
+        tx.commit().await?;
+
+        Ok(codes)
+    }
+
+    /// Challenge the user signing in for the second factor, if it has enabled two-factor
+    /// authentication or its workspace requires it. The user enrolls a new authenticator with
+    /// the challenge in the latter case.
+    pub async fn start_two_factor(
+        &self,
+        user: &User,
+    ) -> Result<Option<TwoFactorChallenge>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let state = lock_totp_state(&mut tx, user.id).await?;
+        let otpauth_uri = if state.totp_enabled_at.is_some() {
+            None
+        } else if self.is_two_factor_required(user.ws_id).await? {
+            // every challenge shows the same authenticator until the user enables it
+            let enrollment = match &state.totp_secret {
+                Some(secret) => pending_enrollment(secret, &state.email)?,
+                None => enroll(&mut tx, user.id, &state.email).await?,
+            };
+            Some(enrollment.otpauth_uri)
+        } else {
+            return Ok(None);
+        };
+
+        let id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO two_factor_challenges(user_id, expires_at)
+            VALUES($1, CURRENT_TIMESTAMP + $2 * INTERVAL '1 second')
+            RETURNING id
+            "#,
+        )
+        .bind(user.id)
+        .bind(CHALLENGE_DURATION as f64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let claims = TokenClaims {
+            scopes: vec![Scope::TwoFactor],
+            ..TokenClaims::new(user, id)
+        };
+        Ok(Some(TwoFactorChallenge {
+            challenge_token: self.ek.sign_for(claims, CHALLENGE_DURATION)?,
+            expires_in: CHALLENGE_DURATION,
+            otpauth_uri,
+        }))
+    }
+
+    /// Finish the signin to the workspace of the challenge with its code, returns the user and
+    /// the recovery codes if the user enrolled the authenticator with the challenge.
+    pub async fn finish_two_factor(
+        &self,
+        input: &TwoFactorSignin,
+    ) -> Result<(User, Option<RecoveryCodes>), AppError> {
+        let claims = self
+            .dk
+            .verify(&input.challenge_token)
+            .map_err(|e| AppError::TwoFactorError(e.to_string()))?;
+        if !claims.has_scope(Scope::TwoFactor) {
+            return Err(AppError::TwoFactorError(
+                "Not a two-factor challenge".to_string(),
+            ));
+        }
+
+        // counted before checking the code, so failed attempts count too
+        let challenge: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE two_factor_challenges
+            SET attempts = attempts + 1
+            WHERE id = $1 AND user_id = $2 AND used_at IS NULL
+                AND expires_at > CURRENT_TIMESTAMP AND attempts < $3
+            RETURNING id
+            "#,
+        )
+        .bind(claims.sid)
+        .bind(claims.uid)
+        .bind(MAX_CHALLENGE_ATTEMPTS)
+        .fetch_optional(&self.pool)
+        .await?;
+        if challenge.is_none() {
+            return Err(AppError::TwoFactorError(
+                "Challenge used, expired or failed too often, sign in again".to_string(),
+            ));
+        }
+
+        let user = self
+            .find_workspace_user(claims.uid, claims.ws_id)
+            .await?
+            .ok_or_else(|| {
+                AppError::PermissionDenied(format!(
+                    "User {} is not an active member of workspace {}",
+                    claims.uid, claims.ws_id
+                ))
+            })?;
+
+        let mut tx = self.pool.begin().await?;
+        let state = lock_totp_state(&mut tx, claims.uid).await?;
+        let codes = if state.totp_enabled_at.is_some() {
+            self.verify_second_factor(&mut tx, claims.uid, &input.code)
+                .await?;
+            None
+        } else if verify_totp(&mut tx, claims.uid, &state, &input.code).await? {
+            Some(enable(&mut tx, claims.uid).await?)
+        } else {
+            return Err(AppError::TwoFactorError("Invalid code".to_string()));
+        };
+        sqlx::query("UPDATE two_factor_challenges SET used_at = CURRENT_TIMESTAMP WHERE id = $1")
+            .bind(claims.sid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok((user, codes))
+    }
+
+    /// Users without two-factor authentication can't get tokens to workspaces requiring it.
+    pub async fn ensure_two_factor(&self, user_id: i64, ws_id: i64) -> Result<(), AppError> {
+        let enabled: bool =
+            sqlx::query_scalar("SELECT totp_enabled_at IS NOT NULL FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+        if !enabled && self.is_two_factor_required(ws_id).await? {
+            return Err(AppError::TwoFactorRequired(format!(
+                "Workspace {ws_id} requires two-factor authentication, enable it or sign in again"
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn is_two_factor_required(&self, ws_id: i64) -> Result<bool, AppError> {
+        let required: Option<bool> =
+            sqlx::query_scalar("SELECT require_two_factor FROM workspaces WHERE id = $1")
+                .bind(ws_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(required.unwrap_or(false))
+    }
+
+    /// Check the code of the authenticator, or use up a recovery code.
+    async fn verify_second_factor(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: i64,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let state = lock_totp_state(tx, user_id).await?;
+        if state.totp_enabled_at.is_none() {
+            return Err(AppError::PermissionDenied(
+                "Two-factor authentication is not enabled".to_string(),
+            ));
+        }
+        if verify_totp(tx, user_id, &state, code).await? {
+            return Ok(());
+        }
+
+        let code = code.trim().to_lowercase();
+        let hashes: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT id, code_hash FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_all(&mut **tx)
+        .await?;
+        for (id, hash) in hashes {
+            if verify_password(&code, &hash)? {
+                sqlx::query("UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP WHERE id = $1")
+                    .bind(id)
+                    .execute(&mut **tx)
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        Err(AppError::TwoFactorError("Invalid code".to_string()))
+    }
+}
+
+/// Lock the user, so a code can't be used twice by concurrent requests.
+async fn lock_totp_state(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+) -> Result<TotpState, AppError> {
+    let state = sqlx::query_as(
+        r#"
+        SELECT email, totp_secret, totp_enabled_at, totp_last_step
+        FROM users
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    state.ok_or_else(|| AppError::NotFound(format!("user id {user_id}")))
+}
+
+/// Store a new pending secret, replacing the one enrolled before.
+async fn enroll(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    email: &str,
+) -> Result<TotpEnrollment, AppError> {
+    let mut secret = vec![0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    let enrollment = new_enrollment(secret, email)?;
+    sqlx::query("UPDATE users SET totp_secret = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(&enrollment.secret)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(enrollment)
+}
+
+/// The enrollment of the secret the user hasn't enabled yet.
+fn pending_enrollment(secret: &str, email: &str) -> Result<TotpEnrollment, AppError> {
+    new_enrollment(decode_secret(secret)?, email)
+}
+
+fn new_enrollment(secret: Vec<u8>, email: &str) -> Result<TotpEnrollment, AppError> {
+    let totp = new_totp(secret, email)?;
+    Ok(TotpEnrollment {
+        secret: totp.get_secret_base32(),
+        otpauth_uri: totp.get_url(),
+    })
+}
+
+async fn enable(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+) -> Result<RecoveryCodes, AppError> {
+    sqlx::query("UPDATE users SET totp_enabled_at = CURRENT_TIMESTAMP WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    insert_recovery_codes(tx, user_id).await
+}
+
+/// Check the code against the steps around now, newer than the step of the last code used.
+async fn verify_totp(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    state: &TotpState,
+    code: &str,
+) -> Result<bool, AppError> {
+    let Some(secret) = &state.totp_secret else {
+        return Ok(false);
+    };
+    let totp = new_totp(decode_secret(secret)?, &state.email)?;
+
+    let now = Utc::now().timestamp() as u64 / TOTP_STEP;
+    let step = [now - 1, now, now + 1]
+        .into_iter()
+        .filter(|step| *step as i64 > state.totp_last_step)
+        .find(|step| totp.generate(step * TOTP_STEP) == code.trim());
+    let Some(step) = step else {
+        return Ok(false);
+    };
+    sqlx::query("UPDATE users SET totp_last_step = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(step as i64)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(true)
+}
+
+async fn insert_recovery_codes(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+) -> Result<RecoveryCodes, AppError> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    let mut recovery_codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let mut buf = [0u8; 5];
+        OsRng.fill_bytes(&mut buf);
+        let code = hex::encode(buf);
+        let code = format!("{}-{}", &code[..5], &code[5..]);
+        sqlx::query("INSERT INTO recovery_codes(user_id, code_hash) VALUES($1, $2)")
+            .bind(user_id)
+            .bind(hash_password(&code)?)
+            .execute(&mut **tx)
+            .await?;
+        recovery_codes.push(code);
+    }
+
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+fn decode_secret(secret: &str) -> Result<Vec<u8>, AppError> {
+    let secret = totp_rs::Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("invalid totp secret: {e:?}"))?;
+    Ok(secret)
+}
+
+fn new_totp(secret: Vec<u8>, email: &str) -> Result<TOTP, AppError> {
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        1,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        email.to_string(),
+    )
+    .context("invalid totp")?;
+    Ok(totp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The code of the authenticator of the secret, `offset` seconds from now.
+    fn totp_code(secret: &str, offset: u64) -> anyhow::Result<String> {
+        let secret = totp_rs::Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+        let totp = new_totp(secret, "test@acme.com")?;
+        Ok(totp.generate(Utc::now().timestamp() as u64 + offset))
+    }
+
+    #[tokio::test]
+    async fn totp_enrollment_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.unwrap();
+        assert!(state.start_two_factor(&user).await?.is_none());
+
+        let enrollment = state.enroll_totp(1).await?;
+        assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/"));
+        let input = TotpCode {
+            code: "000000".to_string(),
+        };
+        let err = state.enable_totp(1, &input).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorError(_)));
+        let input = TotpCode {
+            code: totp_code(&enrollment.secret, 0)?,
+        };
+        let recovery_codes = state.enable_totp(1, &input).await?.recovery_codes;
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+        let err = state.enroll_totp(1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // signin is challenged now, a code can't be used twice
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        assert!(challenge.otpauth_uri.is_none());
+        let mut signin = TwoFactorSignin {
+            challenge_token: challenge.challenge_token,
+            code: input.code,
+        };
+        let err = state.finish_two_factor(&signin).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorError(_)));
+        signin.code = totp_code(&enrollment.secret, TOTP_STEP)?;
+        let (signed_in, codes) = state.finish_two_factor(&signin).await?;
+        assert_eq!(signed_in.id, 1);
+        assert!(codes.is_none());
+        let err = state.finish_two_factor(&signin).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorError(_)));
+
+        // recovery codes work once
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        let mut signin = TwoFactorSignin {
+            challenge_token: challenge.challenge_token,
+            code: recovery_codes[0].to_uppercase(),
+        };
+        state.finish_two_factor(&signin).await?;
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        signin.challenge_token = challenge.challenge_token;
+        let err = state.finish_two_factor(&signin).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorError(_)));
+
+        // the signin finishes in the workspace of the challenge only
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        sqlx::query(
+            "UPDATE workspace_members SET deactivated_at = CURRENT_TIMESTAMP WHERE ws_id = 1 AND user_id = 1",
+        )
+        .execute(&state.pool)
+        .await?;
+        let signin = TwoFactorSignin {
+            challenge_token: challenge.challenge_token,
+            code: recovery_codes[1].clone(),
+        };
+        let err = state.finish_two_factor(&signin).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let input = TotpCode {
+            code: recovery_codes[1].clone(),
+        };
+        state.disable_totp(1, &input).await?;
+        assert!(state.start_two_factor(&user).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_requiring_two_factor_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE workspaces SET require_two_factor = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let err = state.ensure_two_factor(2, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorRequired(_)));
+
+        // the user enrolls with the challenge
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        let uri = challenge.otpauth_uri.unwrap();
+        let totp = TOTP::from_url(&uri)?;
+        // signing in again shows the same authenticator
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        assert_eq!(challenge.otpauth_uri.unwrap(), uri);
+        let mut signin = TwoFactorSignin {
+            challenge_token: challenge.challenge_token,
+            code: "000000".to_string(),
+        };
+        // too many attempts fail the challenge
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS - 1 {
+            let err = state.finish_two_factor(&signin).await.unwrap_err();
+            assert!(matches!(err, AppError::TwoFactorError(_)));
+        }
+        signin.code = totp.generate_current()?;
+        let (_, codes) = state.finish_two_factor(&signin).await?;
+        assert_eq!(codes.unwrap().recovery_codes.len(), RECOVERY_CODE_COUNT);
+        state.ensure_two_factor(2, 1).await?;
+
+        let challenge = state.start_two_factor(&user).await?.unwrap();
+        assert!(challenge.otpauth_uri.is_none());
+        signin.challenge_token = challenge.challenge_token;
+        signin.code = "000000".to_string();
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            let err = state.finish_two_factor(&signin).await.unwrap_err();
+            assert!(matches!(err, AppError::TwoFactorError(_)));
+        }
+        signin.code = totp_code(&totp.get_secret_base32(), TOTP_STEP)?;
+        let err = state.finish_two_factor(&signin).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorError(_)));
+
+        // required by the workspace, it can't be disabled
+        let input = TotpCode {
+            code: totp_code(&totp.get_secret_base32(), TOTP_STEP)?,
+        };
+        let err = state.disable_totp(2, &input).await.unwrap_err();
+        assert!(matches!(err, AppError::TwoFactorRequired(_)));
+        Ok(())
+    }
+}
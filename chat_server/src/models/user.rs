@@ -4,8 +4,9 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
     Argon2, PasswordHash, PasswordVerifier,
 };
-use chat_core::{ChatUser, User};
+use chat_core::{ChatUser, User, Workspace};
 use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
 use utoipa::ToSchema;
 
 use crate::{error::AppError, AppState};
@@ -21,6 +22,9 @@ pub struct CreateUser {
     pub workspace: String,
     /// Password of the user
     pub password: String,
+    /// Invite token, required to join an existing workspace
+    #[serde(default)]
+    pub invite: Option<String>,
 }
 
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
@@ -51,22 +55,60 @@ impl AppState {
         Ok(user)
     }
 
-    // TODO: use transaction for workspace creation and user creation.
+    /// Create the user, and its workspace if it doesn't exist yet, in one transaction.
+    /// The first user of a workspace becomes its owner, others need an invite from the owner.
     pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
-        // check if email exists
-        let user = self.find_user_by_email(&input.email).await?;
-        if user.is_some() {
-            return Err(AppError::EmailAlreadyExists(input.email.clone()));
-        };
+        let password_hash = hash_password(&input.password)?;
+        let mut tx = self.pool.begin().await?;
+        let user = self
+            .insert_user(&mut tx, input, Some(password_hash), false)
+            .await?;
+        tx.commit().await?;
+
+        Ok(user)
+    }
 
-        // check if workspace exists, if not, create one
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+    /// Insert the user and its membership in the transaction, `input.password` is ignored.
+    /// Users of the OIDC provider are trusted to join the workspaces of its config without an
+    /// invite.
+    pub(crate) async fn insert_user(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        input: &CreateUser,
+        password_hash: Option<String>,
+        trusted: bool,
+    ) -> Result<User, AppError> {
+        // a concurrent signup creating the same workspace makes the insert wait for its commit
+        let ws: Option<Workspace> = sqlx::query_as(
+            r#"
+            INSERT INTO workspaces(name, owner_id)
+            VALUES($1, 0)
+            ON CONFLICT (name) DO NOTHING
+            RETURNING id, name, owner_id, created_at
+            "#,
+        )
+        .bind(&input.workspace)
+        .fetch_optional(&mut **tx)
+        .await?;
+        // joining an existing workspace needs an invite
+        let (ws, is_new) = match ws {
+            Some(ws) => (ws, true),
+            None => {
+                let ws = sqlx::query_as(
+                    r#"
+                    SELECT id, name, owner_id, created_at
+                    FROM workspaces
+                    WHERE name = $1
+                    "#,
+                )
+                .bind(&input.workspace)
+                .fetch_one(&mut **tx)
+                .await?;
+                (ws, false)
+            }
         };
 
-        // create a new user
-        let password_hash = hash_password(&input.password)?;
+        // the unique email index decides between concurrent signups with the same email
         let mut user: User = sqlx::query_as(
             r#"
             INSERT INTO users(ws_id, email, fullname, password_hash)
@@ -78,32 +120,75 @@ impl AppState {
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
-        .fetch_one(&self.pool)
-        .await?;
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                AppError::EmailAlreadyExists(input.email.clone())
+            }
+            e => e.into(),
+        })?;
 
-        user.ws_name = ws.name.clone();
+        sqlx::query(
+            r#"
+            INSERT INTO workspace_members(ws_id, user_id)
+            VALUES($1, $2)
+            "#,
+        )
+        .bind(ws.id)
+        .bind(user.id)
+        .execute(&mut **tx)
+        .await?;
 
-        // update workspace owner
-        if ws.owner_id == 0 {
-            self.update_workspace_owner(ws.id as _, user.id as _)
+        if is_new {
+            sqlx::query(
+                r#"
+                UPDATE workspaces
+                SET owner_id = $1
+                WHERE id = $2
+                "#,
+            )
+            .bind(user.id)
+            .bind(ws.id)
+            .execute(&mut **tx)
+            .await?;
+        } else if !trusted {
+            let Some(token) = &input.invite else {
+                return Err(AppError::PermissionDenied(format!(
+                    "An invite is required to join workspace {}",
+                    ws.name
+                )));
+            };
+            self.redeem_invite(tx, token, Some(ws.id), &input.email)
                 .await?;
         }
+        user.ws_name = ws.name;
 
         Ok(user)
     }
 
     pub async fn verify_user(&self, input: &SigninUser) -> Result<Option<User>, AppError> {
+        // sign in to the last used workspace, or to another one the user is still active in
         let user: Option<User> = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1",
+            r#"
+            SELECT u.id, m.ws_id, u.fullname, u.email, u.password_hash, u.created_at
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id AND m.deactivated_at IS NULL
+            WHERE u.email = $1
+            ORDER BY m.ws_id = u.ws_id DESC, m.created_at
+            LIMIT 1
+            "#,
         )
         .bind(&input.email)
         .fetch_optional(&self.pool)
         .await?;
         match user {
             Some(mut user) => {
-                let password_hash = mem::take(&mut user.password_hash);
-                let is_valid =
-                    verify_password(&input.password, &password_hash.unwrap_or_default())?;
+                // users of the OIDC provider have no password
+                let Some(password_hash) = mem::take(&mut user.password_hash) else {
+                    return Ok(None);
+                };
+                let is_valid = verify_password(&input.password, &password_hash)?;
                 if is_valid {
                     // load ws_name, ws should exist
                     let ws = self.find_workspace_by_id(user.ws_id as _).await?.unwrap();
@@ -117,6 +202,60 @@ impl AppState {
         }
     }
 
+    /// The user signed in to the last used workspace, or to another one the user is still
+    /// active in.
+    pub async fn find_signin_user(&self, id: i64) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            SELECT u.id, m.ws_id, w.name AS ws_name, u.fullname, u.email, u.created_at
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id AND m.deactivated_at IS NULL
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE u.id = $1
+            ORDER BY m.ws_id = u.ws_id DESC, m.created_at
+            LIMIT 1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    /// The user signed in to the workspace, if it is an active member of it.
+    pub async fn find_workspace_user(&self, id: i64, ws_id: i64) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            SELECT u.id, m.ws_id, w.name AS ws_name, u.fullname, u.email, u.created_at
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id AND m.deactivated_at IS NULL
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE u.id = $1 AND m.ws_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    /// Users who are not an active member of the workspace of their token are rejected.
+    pub async fn is_workspace_member(&self, ws_id: i64, user_id: i64) -> Result<bool, AppError> {
+        let active = sqlx::query_scalar(
+            r#"
+            SELECT deactivated_at IS NULL
+            FROM workspace_members
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(active.unwrap_or(false))
+    }
+
     #[allow(unused)]
     pub async fn fetch_chat_user_by_id(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
@@ -132,13 +271,36 @@ impl AppState {
         Ok(users)
     }
 
+    pub async fn fetch_ws_chat_user_by_id(
+        &self,
+        ids: &[i64],
+        ws_id: u64,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id
+            WHERE u.id = ANY($1) AND m.ws_id = $2 AND m.deactivated_at IS NULL"#,
+        )
+        .bind(ids)
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
     #[allow(dead_code)]
     pub async fn fetch_chat_users(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        SELECT id, fullname, email
-        FROM users
-        WHERE ws_id = $1"#,
+        SELECT u.id, u.fullname, u.email, COALESCE(p.status, 'offline') AS presence
+        FROM users u
+        JOIN workspace_members m ON m.user_id = u.id AND m.deactivated_at IS NULL
+        LEFT JOIN user_presence p ON p.user_id = u.id
+        WHERE m.ws_id = $1
+        ORDER BY u.id"#,
         )
         .bind(ws_id as i64)
         .fetch_all(&self.pool)
@@ -148,7 +310,7 @@ impl AppState {
     }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+pub(crate) fn hash_password(password: &str) -> Result<String, AppError> {
     let slat = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     let password_hash = argon2
@@ -157,7 +319,7 @@ fn hash_password(password: &str) -> Result<String, AppError> {
     Ok(password_hash)
 }
 
-fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
     let argon2 = Argon2::default();
     let password_hash = PasswordHash::new(password_hash)?;
     let is_valid = argon2
@@ -174,6 +336,7 @@ impl CreateUser {
             fullname: fullname.to_string(),
             email: email.to_string(),
             password: password.to_string(),
+            invite: None,
         }
     }
 }
@@ -208,7 +371,7 @@ mod tests {
         let email = "hedon@gmail.com";
         let fullname = "Hedon";
         let password = "hedon";
-        let input = CreateUser::new("none", fullname, email, password);
+        let input = CreateUser::new("hedon", fullname, email, password);
         let user = state.create_user(&input).await?;
         assert_eq!(user.email, email);
         assert_eq!(user.fullname, fullname);
@@ -230,7 +393,7 @@ mod tests {
     #[tokio::test]
     async fn create_duplicated_user_should_fail() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("none", "Hedon", "hedon@gmail.com", "hedon");
+        let input = CreateUser::new("hedon", "Hedon", "hedon@gmail.com", "hedon");
 
         state.create_user(&input).await?;
         let ret = state.create_user(&input).await;
@@ -243,6 +406,52 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn concurrent_signups_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // the same email, only one signup succeeds
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let state = state.clone();
+                let input = CreateUser::new("race", &format!("Racer {i}"), "race@acme.org", "pwd");
+                tokio::spawn(async move { state.create_user(&input).await })
+            })
+            .collect();
+        let mut created = 0;
+        for task in tasks {
+            match task.await? {
+                Ok(_) => created += 1,
+                Err(AppError::EmailAlreadyExists(email)) => assert_eq!(email, "race@acme.org"),
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+        assert_eq!(created, 1);
+
+        // different emails creating the same workspace, the others need an invite
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let state = state.clone();
+                let input =
+                    CreateUser::new("newco", "Founder", &format!("founder{i}@newco.org"), "pwd");
+                tokio::spawn(async move { state.create_user(&input).await })
+            })
+            .collect();
+        let mut users = Vec::new();
+        for task in tasks {
+            match task.await? {
+                Ok(user) => users.push(user),
+                Err(AppError::PermissionDenied(_)) => {}
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+        assert_eq!(users.len(), 1);
+        let ws = state.find_workspace_by_name("newco").await?.unwrap();
+        assert_eq!(users[0].ws_id, ws.id);
+        assert_eq!(users[0].id, ws.owner_id);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn find_user_by_id_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -1,7 +1,94 @@
-use chat_core::{ChatUser, Workspace};
-use sqlx::PgPool;
+use chat_core::{ChatUser, User, Workspace};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
 
-use crate::{AppError, AppState};
+use crate::{models::revoke_workspace_sessions, AppError, AppState};
+
+const MAX_WORKSPACE_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceSettings {
+    /// Members can create public channels
+    pub public_channels: bool,
+    /// Seconds until an invite expires if not given, never if not set
+    pub invite_expires_in: Option<i64>,
+    /// Members have to verify their email before they can sign in
+    pub require_verified_email: bool,
+    /// Members have to use two-factor authentication to sign in
+    pub require_two_factor: bool,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateWorkspaceSettings {
+    /// Members can create public channels
+    pub public_channels: bool,
+    /// Seconds until an invite expires if not given, never if not set
+    pub invite_expires_in: Option<i64>,
+    /// Members have to verify their email before they can sign in, keep the current setting if
+    /// not set
+    #[serde(default)]
+    pub require_verified_email: Option<bool>,
+    /// Members have to use two-factor authentication to sign in, keep the current setting if
+    /// not set
+    #[serde(default)]
+    pub require_two_factor: Option<bool>,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceDetail {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub workspace: Workspace,
+    #[sqlx(flatten)]
+    pub settings: WorkspaceSettings,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct UpdateWorkspace {
+    /// New name of the workspace, keep the current one if not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Transfer the ownership to another active member
+    #[serde(default)]
+    pub owner_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, ToSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum WorkspaceRole {
+    Owner,
+    #[default]
+    Member,
+}
+
+#[derive(Debug, Clone, FromRow, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceMember {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    #[sqlx(skip)]
+    pub role: WorkspaceRole,
+    /// Deactivated members can't sign in to the workspace
+    pub deactivated_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct UpdateMember {
+    /// Activate the user again, or deactivate it
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct JoinWorkspace {
+    /// Invite token from the owner of the workspace
+    pub invite: String,
+}
 
 impl AppState {
     pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<Workspace, AppError> {
@@ -52,9 +139,10 @@ impl AppState {
     pub async fn fetch_all_chat_users(id: u64, pool: &PgPool) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        SELECT id, fullname, email
-        FROM users
-        WHERE ws_id = $1 order by id
+        SELECT u.id, u.fullname, u.email
+        FROM users u
+        JOIN workspace_members m ON m.user_id = u.id
+        WHERE m.ws_id = $1 order by u.id
         "#,
         )
         .bind(id as i64)
@@ -72,7 +160,8 @@ impl AppState {
         let ws = sqlx::query_as(
             r#"UPDATE workspaces
             SET owner_id = $1
-            WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
+            WHERE id = $2 and EXISTS (SELECT 1 FROM workspace_members
+                WHERE ws_id = $2 AND user_id = $1 AND deactivated_at IS NULL)
             RETURNING id, name, owner_id, created_at"#,
         )
         .bind(owner_id as i64)
@@ -82,11 +171,402 @@ impl AppState {
 
         Ok(ws)
     }
+
+    pub async fn get_workspace(&self, id: u64) -> Result<WorkspaceDetail, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            SELECT id, name, owner_id, created_at, public_channels, invite_expires_in,
+                require_verified_email, require_two_factor
+            FROM workspaces
+            WHERE id = $1"#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        ws.ok_or_else(|| AppError::NotFound(format!("workspace id {id}")))
+    }
+
+    /// Rename the workspace or transfer its ownership, only the owner can do this.
+    pub async fn update_workspace(
+        &self,
+        input: UpdateWorkspace,
+        id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceDetail, AppError> {
+        self.verify_ws_owner(id, user_id).await?;
+
+        if let Some(name) = &input.name {
+            if name.is_empty() || name.chars().count() > MAX_WORKSPACE_NAME_LEN {
+                return Err(AppError::WorkspaceError(format!(
+                    "Workspace name must be 1 to {MAX_WORKSPACE_NAME_LEN} characters"
+                )));
+            }
+        }
+        if let Some(owner_id) = input.owner_id {
+            let member = self.find_workspace_member(id, owner_id as _).await?;
+            if member.is_none_or(|m| m.deactivated_at.is_some()) {
+                return Err(AppError::WorkspaceError(format!(
+                    "User {owner_id} is not an active member of the workspace"
+                )));
+            }
+        }
+
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET name = COALESCE($2, name), owner_id = COALESCE($3, owner_id)
+            WHERE id = $1
+            RETURNING id, name, owner_id, created_at, public_channels, invite_expires_in,
+                require_verified_email, require_two_factor"#,
+        )
+        .bind(id as i64)
+        .bind(&input.name)
+        .bind(input.owner_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                AppError::WorkspaceError(format!(
+                    "Workspace {} already exists",
+                    input.name.unwrap_or_default()
+                ))
+            }
+            e => e.into(),
+        })?;
+
+        Ok(ws)
+    }
+
+    pub async fn update_workspace_settings(
+        &self,
+        input: UpdateWorkspaceSettings,
+        id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceSettings, AppError> {
+        self.verify_ws_owner(id, user_id).await?;
+        if input.invite_expires_in.is_some_and(|v| v <= 0) {
+            return Err(AppError::WorkspaceError(
+                "Invites must expire in the future".to_string(),
+            ));
+        }
+
+        let settings = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET public_channels = $2, invite_expires_in = $3,
+                require_verified_email = COALESCE($4, require_verified_email),
+                require_two_factor = COALESCE($5, require_two_factor)
+            WHERE id = $1
+            RETURNING public_channels, invite_expires_in, require_verified_email,
+                require_two_factor"#,
+        )
+        .bind(id as i64)
+        .bind(input.public_channels)
+        .bind(input.invite_expires_in)
+        .bind(input.require_verified_email)
+        .bind(input.require_two_factor)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(settings)
+    }
+
+    /// List all users of the workspace with their roles, deactivated ones included.
+    pub async fn list_workspace_members(
+        &self,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Vec<WorkspaceMember>, AppError> {
+        let ws = self.verify_ws_owner(id, user_id).await?;
+        let mut members: Vec<WorkspaceMember> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, m.deactivated_at, m.created_at
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id
+            WHERE m.ws_id = $1
+            ORDER BY u.id"#,
+        )
+        .bind(id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for member in members.iter_mut() {
+            member.role = ws.role_of(member.id);
+        }
+        Ok(members)
+    }
+
+    /// Deactivate a member of the workspace or activate it again. Deactivating signs the member
+    /// out of the workspace.
+    pub async fn update_workspace_member(
+        &self,
+        input: UpdateMember,
+        id: u64,
+        user_id: u64,
+        member_id: u64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let ws = self.verify_ws_owner(id, user_id).await?;
+        if ws.workspace.owner_id == member_id as i64 {
+            return Err(AppError::WorkspaceError(
+                "Workspace owner can't be deactivated".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let member: Option<WorkspaceMember> = sqlx::query_as(
+            r#"
+            UPDATE workspace_members m
+            SET deactivated_at = CASE WHEN $3 THEN NULL ELSE COALESCE(m.deactivated_at, now()) END
+            FROM users u
+            WHERE m.user_id = $1 AND m.ws_id = $2 AND u.id = m.user_id
+            RETURNING u.id, u.fullname, u.email, m.deactivated_at, m.created_at"#,
+        )
+        .bind(member_id as i64)
+        .bind(id as i64)
+        .bind(input.active)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(member) = member else {
+            return Err(AppError::NotFound(format!("member id {member_id}")));
+        };
+        let sids = if input.active {
+            vec![]
+        } else {
+            revoke_workspace_sessions(&mut tx, member_id as _, id as _).await?
+        };
+        tx.commit().await?;
+
+        for sid in sids {
+            self.sessions.remove(sid);
+        }
+        Ok(member)
+    }
+
+    /// Remove a user from the workspace: the user is deactivated, signed out of the workspace
+    /// and leaves all chats. Single and group chats left with less than 2 members are deleted
+    /// with their messages.
+    pub async fn remove_workspace_member(
+        &self,
+        id: u64,
+        user_id: u64,
+        member_id: u64,
+    ) -> Result<(), AppError> {
+        let ws = self.verify_ws_owner(id, user_id).await?;
+        if ws.workspace.owner_id == member_id as i64 {
+            return Err(AppError::WorkspaceError(
+                "Workspace owner can't be removed".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET deactivated_at = COALESCE(deactivated_at, now())
+            WHERE user_id = $1 AND ws_id = $2"#,
+        )
+        .bind(member_id as i64)
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("member id {member_id}")));
+        }
+
+        sqlx::query(
+            r#"
+            DELETE FROM chat_roles r
+            USING chats c
+            WHERE c.id = r.chat_id AND c.ws_id = $2 AND r.user_id = $1"#,
+        )
+        .bind(member_id as i64)
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        let chat_ids: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT id
+            FROM chats
+            WHERE ws_id = $2 AND $1 = ANY(members) AND type IN ('single', 'group')
+                AND cardinality(array_remove(members, $1)) < 2"#,
+        )
+        .bind(member_id as i64)
+        .bind(id as i64)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM messages WHERE chat_id = ANY($1)")
+            .bind(&chat_ids)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM chats WHERE id = ANY($1)")
+            .bind(&chat_ids)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $1)
+            WHERE ws_id = $2 AND $1 = ANY(members)"#,
+        )
+        .bind(member_id as i64)
+        .bind(id as i64)
+        .execute(&mut *tx)
+        .await?;
+        let sids = revoke_workspace_sessions(&mut tx, member_id as _, id as _).await?;
+        tx.commit().await?;
+
+        for sid in sids {
+            self.sessions.remove(sid);
+        }
+        Ok(())
+    }
+
+    /// List the workspaces the user is an active member of.
+    pub async fn list_user_workspaces(&self, user_id: u64) -> Result<Vec<Workspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, w.created_at
+            FROM workspaces w
+            JOIN workspace_members m ON m.ws_id = w.id
+            WHERE m.user_id = $1 AND m.deactivated_at IS NULL
+            ORDER BY w.id"#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    /// Switch the session of the user to another workspace it is an active member of, which
+    /// is also used at the next signin. Returns the user scoped to the workspace.
+    pub async fn switch_workspace(
+        &self,
+        id: u64,
+        user_id: u64,
+        sid: i64,
+    ) -> Result<User, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let user: Option<User> = sqlx::query_as(
+            r#"
+            WITH member AS (
+                SELECT ws_id, user_id
+                FROM workspace_members
+                WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NULL
+            )
+            UPDATE users u
+            SET ws_id = m.ws_id
+            FROM member m
+            WHERE u.id = m.user_id
+            RETURNING u.id, u.ws_id, u.fullname, u.email, u.created_at"#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(mut user) = user else {
+            return Err(AppError::PermissionDenied(format!(
+                "Not an active member of workspace {id}"
+            )));
+        };
+        sqlx::query("UPDATE sessions SET ws_id = $1 WHERE id = $2 AND user_id = $3")
+            .bind(id as i64)
+            .bind(sid)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        user.ws_name = self.get_workspace(id).await?.workspace.name;
+        Ok(user)
+    }
+
+    /// Join another workspace with an invite, a deactivated member is activated again.
+    pub async fn join_workspace(
+        &self,
+        input: &JoinWorkspace,
+        user: &User,
+    ) -> Result<Workspace, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ws_id = self
+            .redeem_invite(&mut tx, &input.invite, None, &user.email)
+            .await?;
+
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO workspace_members(ws_id, user_id)
+            VALUES($1, $2)
+            ON CONFLICT (ws_id, user_id)
+                DO UPDATE SET deactivated_at = NULL
+                WHERE workspace_members.deactivated_at IS NOT NULL"#,
+        )
+        .bind(ws_id)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::WorkspaceError(format!(
+                "Already a member of workspace {ws_id}"
+            )));
+        }
+        tx.commit().await?;
+
+        Ok(self.get_workspace(ws_id as _).await?.workspace)
+    }
+
+    /// Get the workspace if the user owns it.
+    pub(crate) async fn verify_ws_owner(
+        &self,
+        id: u64,
+        user_id: u64,
+    ) -> Result<WorkspaceDetail, AppError> {
+        let ws = self.get_workspace(id).await?;
+        if ws.workspace.owner_id != user_id as i64 {
+            return Err(AppError::PermissionDenied(
+                "Only the owner of the workspace can do this".to_string(),
+            ));
+        }
+        Ok(ws)
+    }
+
+    async fn find_workspace_member(
+        &self,
+        id: u64,
+        user_id: u64,
+    ) -> Result<Option<WorkspaceMember>, AppError> {
+        let member = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, m.deactivated_at, m.created_at
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id
+            WHERE u.id = $1 AND m.ws_id = $2"#,
+        )
+        .bind(user_id as i64)
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(member)
+    }
+}
+
+impl WorkspaceDetail {
+    fn role_of(&self, user_id: i64) -> WorkspaceRole {
+        if self.workspace.owner_id == user_id {
+            WorkspaceRole::Owner
+        } else {
+            WorkspaceRole::Member
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::models::CreateUser;
+    use crate::models::{CreateChat, CreateInvite, CreateUser, SigninUser};
+    use chat_core::{Presence, TokenClaims};
 
     use super::*;
 
@@ -94,12 +574,15 @@ mod tests {
     async fn workspace_should_create_and_set_owner() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let ws = state.create_workspace("test", 0).await.unwrap();
+        assert_eq!(ws.name, "test");
+        assert_eq!(ws.owner_id, 0);
 
-        let input = CreateUser::new(&ws.name, "hedon", "hedon@example.com", "123456");
+        // the first user of a new workspace owns it
+        let input = CreateUser::new("hdws", "hedon", "hedon@example.com", "123456");
         let user = state.create_user(&input).await.unwrap();
-
-        assert_eq!(ws.name, "test");
-        assert_eq!(user.ws_id, ws.id);
+        let ws = state.find_workspace_by_id(user.ws_id as _).await?.unwrap();
+        assert_eq!(ws.name, "hdws");
+        assert_eq!(ws.owner_id, user.id);
 
         let ws = state
             .update_workspace_owner(ws.id as _, user.id as _)
@@ -109,6 +592,196 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn update_workspace_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = UpdateWorkspace {
+            name: Some("acme2".to_string()),
+            ..Default::default()
+        };
+        let ws = state.update_workspace(input, 1, 1).await?;
+        assert_eq!(ws.workspace.name, "acme2");
+        assert!(ws.settings.public_channels);
+
+        let input = UpdateWorkspace {
+            name: Some("foo".to_string()),
+            ..Default::default()
+        };
+        let err = state.update_workspace(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::WorkspaceError(_)));
+
+        // transfer the ownership, the previous owner can't update it anymore
+        let input = UpdateWorkspace {
+            owner_id: Some(2),
+            ..Default::default()
+        };
+        let ws = state.update_workspace(input.clone(), 1, 1).await?;
+        assert_eq!(ws.workspace.owner_id, 2);
+        let err = state.update_workspace(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_settings_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = UpdateWorkspaceSettings {
+            public_channels: false,
+            invite_expires_in: Some(3600),
+            require_verified_email: Some(true),
+            require_two_factor: Some(true),
+        };
+        let settings = state.update_workspace_settings(input.clone(), 1, 1).await?;
+        let expected = WorkspaceSettings {
+            public_channels: false,
+            invite_expires_in: Some(3600),
+            require_verified_email: true,
+            require_two_factor: true,
+        };
+        assert_eq!(settings, expected);
+        // clients not knowing about a setting keep it as it is
+        let omitted: UpdateWorkspaceSettings = serde_json::from_value(serde_json::json!({
+            "publicChannels": false, "inviteExpiresIn": 3600
+        }))?;
+        let settings = state.update_workspace_settings(omitted, 1, 1).await?;
+        assert_eq!(settings, expected);
+        let err = state
+            .update_workspace_settings(input, 1, 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let input = CreateChat::new("public", &[1, 2, 3], true);
+        let err = state.create_chat(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let invite = state.create_invite(CreateInvite::default(), 1, 1).await?;
+        assert!(invite.expires_at.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiple_workspaces_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("newco", "Ann", "ann@newco.org", "123456");
+        let owner = state.create_user(&input).await?;
+        let invite = state
+            .create_invite(CreateInvite::default(), owner.ws_id as _, owner.id as _)
+            .await?;
+
+        // jim joins newco, and still signs in to acme
+        let jim = state.find_user_by_id(5).await?.unwrap();
+        let input = JoinWorkspace {
+            invite: invite.token,
+        };
+        let ws = state.join_workspace(&input, &jim).await?;
+        assert_eq!(ws.id, owner.ws_id);
+        let workspaces = state.list_user_workspaces(5).await?;
+        assert_eq!(workspaces.len(), 2);
+        let signin = SigninUser::new("jim@acme.com", "123456");
+        assert_eq!(state.verify_user(&signin).await?.unwrap().ws_id, 1);
+
+        // switching makes newco the workspace to sign in to
+        let (sid, _) = state.create_session(&jim).await?;
+        let user = state.switch_workspace(ws.id as _, 5, sid).await?;
+        assert_eq!(user.ws_id, ws.id);
+        assert_eq!(user.ws_name, "newco");
+        assert_eq!(state.verify_user(&signin).await?.unwrap().ws_id, ws.id);
+        let users = state.fetch_chat_users(ws.id as _).await?;
+        assert_eq!(users.len(), 2);
+
+        // deactivated in newco only
+        let input = UpdateMember { active: false };
+        state
+            .update_workspace_member(input, ws.id as _, owner.id as _, 5)
+            .await?;
+        assert!(!state.is_workspace_member(ws.id, 5).await?);
+        assert!(state.is_workspace_member(1, 5).await?);
+        assert_eq!(state.verify_user(&signin).await?.unwrap().ws_id, 1);
+        let err = state
+            .switch_workspace(ws.id as _, 5, sid)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // a new invite activates jim again, but can't be used by an active member
+        let invite = state
+            .create_invite(CreateInvite::default(), owner.ws_id as _, owner.id as _)
+            .await?;
+        let input = JoinWorkspace {
+            invite: invite.token,
+        };
+        state.join_workspace(&input, &jim).await?;
+        assert!(state.is_workspace_member(ws.id, 5).await?);
+        let err = state.join_workspace(&input, &jim).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_members_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let members = state.list_workspace_members(1, 1).await?;
+        assert_eq!(members.len(), 5);
+        assert_eq!(members[0].role, WorkspaceRole::Owner);
+        assert_eq!(members[1].role, WorkspaceRole::Member);
+        let err = state.list_workspace_members(1, 2).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        // deactivated users are signed out, and can't sign in nor be added to chats
+        let signin = SigninUser::new("jim@acme.com", "123456");
+        let jim = state.verify_user(&signin).await?.unwrap();
+        let (sid, _) = state.create_session(&jim).await?;
+        let claims = TokenClaims::new(&jim, sid);
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_some());
+        let input = UpdateMember { active: false };
+        let member = state.update_workspace_member(input, 1, 1, 5).await?;
+        assert!(member.deactivated_at.is_some());
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_none());
+        assert!(!state.is_workspace_member(1, 5).await?);
+        assert!(state.verify_user(&signin).await?.is_none());
+        let input = CreateChat::new("", &[1, 5], false);
+        let err = state.create_chat(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::CreateChatError(_)));
+
+        let input = UpdateMember { active: true };
+        state.update_workspace_member(input, 1, 1, 5).await?;
+        assert!(state.is_workspace_member(1, 5).await?);
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_none());
+
+        let input = UpdateMember { active: false };
+        let err = state
+            .update_workspace_member(input, 1, 1, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::WorkspaceError(_)));
+
+        // removed users are signed out and leave all chats
+        let user = state.find_user_by_id(3).await?.unwrap();
+        let (sid, _) = state.create_session(&user).await?;
+        let claims = TokenClaims::new(&user, sid);
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_some());
+        state.remove_workspace_member(1, 1, 3).await?;
+        assert!(state.sessions.load(&claims, &state.pool).await?.is_none());
+        assert!(!state.is_workspace_member(1, 3).await?);
+        let chats = state.fetch_chats(1, 1).await?;
+        assert!(chats.iter().all(|c| !c.members.contains(&3)));
+        assert!(chats.iter().any(|c| c.id == 4));
+
+        // a single chat can't be left with one member, it goes with its messages
+        sqlx::query("INSERT INTO messages(chat_id, sender_id, content) VALUES (3, 2, 'Bye')")
+            .execute(&state.pool)
+            .await?;
+        state.remove_workspace_member(1, 1, 2).await?;
+        assert!(state.get_chat_by_id(3).await?.is_none());
+        let err = state.remove_workspace_member(1, 1, 42).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_should_find_by_id() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -134,6 +807,21 @@ mod tests {
         assert_eq!(users.len(), 5);
         assert_eq!(users[0].email, "hedon@acme.com");
         assert_eq!(users[0].fullname, "Hedon");
+        assert_eq!(users[0].presence, Presence::Offline);
+
+        sqlx::query("INSERT INTO user_presence(user_id, status) VALUES (1, 'online')")
+            .execute(&state.pool)
+            .await?;
+        let users = state.fetch_chat_users(1).await?;
+        assert_eq!(users[0].presence, Presence::Online);
+        assert_eq!(users[1].presence, Presence::Offline);
+
+        // deactivated members are not listed
+        let input = UpdateMember { active: false };
+        state.update_workspace_member(input, 1, 1, 5).await?;
+        let users = state.fetch_chat_users(1).await?;
+        assert_eq!(users.len(), 4);
+        assert!(users.iter().all(|u| u.id != 5));
         Ok(())
     }
 }
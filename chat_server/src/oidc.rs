@@ -0,0 +1,255 @@
+use anyhow::Context;
+use jwt_simple::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+use crate::{config::OidcConfig, AppError};
+
+const SCOPES: &str = "openid email profile";
+
+/// Client of the authorization code flow of an OpenID Connect provider.
+pub struct OidcClient {
+    pub config: OidcConfig,
+    http: reqwest::Client,
+    // discovered on the first login
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// Only the RSA keys of RS256, the signature algorithm all providers support.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+}
+
+/// The account at the provider, mapped from the claims of the id token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidcUser {
+    pub issuer: String,
+    pub subject: String,
+    pub email: String,
+    /// Only verified emails link the account to an existing user
+    pub email_verified: bool,
+    pub fullname: String,
+    pub workspace: String,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    /// Url of the provider to redirect the browser to.
+    pub async fn authorize_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, AppError> {
+        let metadata = self.metadata().await?;
+        let challenge =
+            Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(code_verifier.as_bytes()))
+                .context("encode code challenge failed")?;
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", SCOPES),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .context("invalid authorization endpoint")?;
+
+        Ok(url.to_string())
+    }
+
+    /// Exchange the code for the id token of the login, and map its verified claims.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<OidcUser, AppError> {
+        let metadata = self.metadata().await?;
+        let rsp = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await
+            .context("token request failed")?;
+        if !rsp.status().is_success() {
+            return Err(AppError::OidcError(format!(
+                "token endpoint returned {}",
+                rsp.status()
+            )));
+        }
+        let token: TokenResponse = rsp.json().await.context("invalid token response")?;
+
+        let claims = self
+            .verify_id_token(metadata, &token.id_token, nonce)
+            .await?;
+        self.map_claims(metadata, claims)
+    }
+
+    async fn verify_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<JWTClaims<Map<String, Value>>, AppError> {
+        let kid = Token::decode_metadata(id_token)
+            .map_err(|e| AppError::OidcError(format!("invalid id token: {e}")))?
+            .key_id()
+            .map(str::to_string);
+        // keys rotate at the provider, so they are fetched for every login
+        let jwks: Jwks = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|rsp| rsp.error_for_status())
+            .context("jwks request failed")?
+            .json()
+            .await
+            .context("invalid jwks")?;
+        let jwk = jwks
+            .keys
+            .iter()
+            .find(|k| k.kty == "RSA" && (kid.is_none() || k.kid == kid))
+            .ok_or_else(|| AppError::OidcError("no key to verify the id token".to_string()))?;
+        let key = jwk.public_key()?;
+
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[&metadata.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.config.client_id])),
+            required_nonce: Some(nonce.to_string()),
+            ..VerificationOptions::default()
+        };
+        key.verify_token(id_token, Some(opts))
+            .map_err(|e| AppError::OidcError(format!("invalid id token: {e}")))
+    }
+
+    fn map_claims(
+        &self,
+        metadata: &ProviderMetadata,
+        claims: JWTClaims<Map<String, Value>>,
+    ) -> Result<OidcUser, AppError> {
+        let mapping = &self.config.claims;
+        let custom = &claims.custom;
+        let claim = |name: &str| custom.get(name).and_then(Value::as_str);
+
+        let subject = claims
+            .subject
+            .ok_or_else(|| AppError::OidcError("id token without subject".to_string()))?;
+        let email = claim(&mapping.email)
+            .ok_or_else(|| AppError::OidcError(format!("id token without {}", mapping.email)))?;
+        let email_verified = custom
+            .get("email_verified")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let fullname = claim(&mapping.fullname).unwrap_or(email);
+        let workspace = mapping
+            .workspace
+            .as_deref()
+            .and_then(claim)
+            .unwrap_or(&self.config.workspace);
+
+        Ok(OidcUser {
+            issuer: metadata.issuer.clone(),
+            subject,
+            email: email.to_string(),
+            email_verified,
+            fullname: fullname.to_string(),
+            workspace: workspace.to_string(),
+        })
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, AppError> {
+        let metadata = self
+            .metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|rsp| rsp.error_for_status())
+                    .context("discovery request failed")?
+                    .json()
+                    .await
+                    .context("invalid discovery document")?;
+                Ok::<_, anyhow::Error>(metadata)
+            })
+            .await?;
+
+        Ok(metadata)
+    }
+}
+
+impl Jwk {
+    #[cfg(test)]
+    pub(crate) fn from_key(key: &RS256PublicKey, kid: &str) -> anyhow::Result<Self> {
+        let components = key.to_components();
+        Ok(Self {
+            kty: "RSA".to_string(),
+            kid: Some(kid.to_string()),
+            n: Base64UrlSafeNoPadding::encode_to_string(components.n)?,
+            e: Base64UrlSafeNoPadding::encode_to_string(components.e)?,
+        })
+    }
+
+    fn public_key(&self) -> Result<RS256PublicKey, AppError> {
+        let decode = |v: &str| {
+            Base64UrlSafeNoPadding::decode_to_vec(v, None)
+                .map_err(|e| AppError::OidcError(format!("invalid jwk: {e}")))
+        };
+        RS256PublicKey::from_components(&decode(&self.n)?, &decode(&self.e)?)
+            .map_err(|e| AppError::OidcError(format!("invalid jwk: {e}")))
+    }
+}
@@ -1,7 +1,14 @@
 use crate::{
     error::ErrorOutput,
     handlers::*,
-    models::{CreateChat, CreateMessage, CreateUser, ListMessages, SigninUser},
+    models::{
+        CreateChat, CreateInvite, CreateMessage, CreateReaction, CreateUser, Invite, JoinWorkspace,
+        ListMessages, MarkRead, MessageEdit, MessagePage, OidcCallback, RecoveryCodes,
+        RefreshSession, RequestEmail, ResetPassword, SearchChat, SearchMessages, SearchResult,
+        SigninUser, TotpCode, TotpEnrollment, TwoFactorChallenge, TwoFactorSignin, UpdateChat,
+        UpdateMember, UpdateMessage, UpdateWorkspace, UpdateWorkspaceSettings, VerifyEmail,
+        WorkspaceDetail, WorkspaceMember, WorkspaceRole, WorkspaceSettings,
+    },
 };
 
 use axum::Router;
@@ -13,7 +20,9 @@ use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
-use chat_core::{Chat, ChatType, ChatUser, Message, User, Workspace};
+use chat_core::{
+    Chat, ChatRole, ChatType, ChatUser, Message, Presence, Reaction, ReadReceipt, User, Workspace,
+};
 
 use crate::{AppState, AuthOutput};
 
@@ -26,16 +35,59 @@ pub(crate) trait OpenApiRouter {
     paths(
         signup_handler,
         signin_handler,
+        refresh_handler,
+        oidc_login_handler,
+        oidc_callback_handler,
+        verify_email_handler,
+        resend_verification_handler,
+        forgot_password_handler,
+        reset_password_handler,
+        two_factor_signin_handler,
+        totp_enroll_handler,
+        totp_enable_handler,
+        totp_disable_handler,
+        recovery_codes_handler,
+        signout_handler,
         list_chat_handler,
         create_chat_handler,
         get_chat_handler,
+        update_chat_handler,
+        delete_chat_handler,
+        typing_handler,
+        mark_read_handler,
+        list_read_receipt_handler,
         list_message_handler,
         list_chat_user_handler,
+        list_workspace_handler,
+        join_workspace_handler,
+        switch_workspace_handler,
+        get_workspace_handler,
+        update_workspace_handler,
+        update_workspace_settings_handler,
+        list_workspace_member_handler,
+        update_workspace_member_handler,
+        remove_workspace_member_handler,
+        create_invite_handler,
+        list_invite_handler,
+        revoke_invite_handler,
+        search_handler,
         send_message_handler,
+        update_message_handler,
+        delete_message_handler,
+        list_message_edit_handler,
+        list_thread_message_handler,
+        add_reaction_handler,
+        remove_reaction_handler,
     ),
     components(
-        schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SigninUser, CreateUser,
-            CreateChat, CreateMessage, ListMessages, AuthOutput, ErrorOutput),
+        schemas(User, Chat, ChatType, ChatRole, ChatUser, Presence, Message, Workspace,
+            SigninUser, CreateUser, RefreshSession, OidcCallback, RequestEmail, VerifyEmail,
+            ResetPassword, TwoFactorChallenge, TwoFactorSignin, TotpEnrollment, TotpCode,
+            RecoveryCodes, CreateChat, UpdateChat, CreateMessage, UpdateMessage, MessageEdit,
+            ListMessages, MessagePage, Reaction, CreateReaction, MarkRead, ReadReceipt,
+            SearchMessages, SearchResult, SearchChat, Invite, CreateInvite, JoinWorkspace,
+            WorkspaceDetail, WorkspaceSettings, UpdateWorkspaceSettings, UpdateWorkspace,
+            WorkspaceMember, WorkspaceRole, UpdateMember, AuthOutput, ErrorOutput),
     ),
     modifiers(&SecurityAddon),
     tags(
@@ -1,6 +1,6 @@
 use std::{net::SocketAddr, time::Duration, vec};
 
-use chat_core::{Chat, ChatType, Message};
+use chat_core::{Chat, ChatType, ChatUser, Message, Presence};
 use futures::StreamExt;
 use reqwest::{
     multipart::{Form, Part},
@@ -9,7 +9,11 @@ use reqwest::{
 use reqwest_eventsource::{Event, EventSource};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::{net::TcpListener, time::sleep};
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{self, UnboundedReceiver},
+    time::timeout,
+};
 
 const WILD_ADDR: &str = "0.0.0.0:0";
 
@@ -24,17 +28,98 @@ struct ChatServer {
     client: reqwest::Client,
 }
 
-struct NotifyServer;
+struct NotifyServer {
+    addr: SocketAddr,
+}
+
+#[derive(Debug)]
+struct SseEvent {
+    event: String,
+    id: String,
+    data: String,
+}
 
 #[tokio::test]
 async fn chat_server_should_work() -> anyhow::Result<()> {
     let (tdb, state) = chat_server::AppState::new_for_test().await?;
     let cs = ChatServer::new(state).await?;
     let db_url = tdb.url();
-    NotifyServer::new(&db_url, &cs.token).await?;
+    let ns = NotifyServer::new(&db_url).await?;
+    let mut events = ns.connect(&cs.token).await?;
+
+    // events carry the rows loaded by the notify server, so wait for each event before the next
+    // change to check it
     let chat = cs.create_chat().await?;
-    let _msg = cs.create_message(chat.id as u64).await?;
-    sleep(Duration::from_secs(1)).await;
+    let message = next_event(&mut events, "NewChat").await?;
+    let chat_event = parse_chat(&message.data)?;
+    assert_eq!(chat_event.name.as_ref().unwrap(), "test");
+    assert_eq!(chat_event.members, vec![1, 2]);
+    assert_eq!(chat_event.r#type, ChatType::PrivateChannel);
+
+    let msg = cs.create_message(chat.id as u64).await?;
+    let message = next_event(&mut events, "NewMessage").await?;
+    let msg_event: Message = serde_json::from_str(&message.data)?;
+    assert_eq!(msg_event.content, "hello");
+    assert_eq!(msg_event.files.len(), 1);
+    assert_eq!(msg_event.sender_id, 1);
+
+    cs.update_message(chat.id as u64, msg.id as u64).await?;
+    let message = next_event(&mut events, "MessageUpdated").await?;
+    let msg_event: Message = serde_json::from_str(&message.data)?;
+    assert_eq!(msg_event.content, "hello again");
+    assert!(msg_event.updated_at.is_some());
+
+    cs.delete_message(chat.id as u64, msg.id as u64).await?;
+    let message = next_event(&mut events, "MessageDeleted").await?;
+    let msg_event: Message = serde_json::from_str(&message.data)?;
+    assert_eq!(msg_event.content, "");
+    assert!(msg_event.deleted_at.is_some());
+
+    cs.update_chat(chat.id as u64).await?;
+    let message = next_event(&mut events, "AddToChat").await?;
+    let chat_event = parse_chat(&message.data)?;
+    assert_eq!(chat_event.name.as_ref().unwrap(), "test");
+    assert_eq!(chat_event.members, vec![1, 2, 3]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn notify_servers_should_share_events() -> anyhow::Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let cs = ChatServer::new(state).await?;
+    let db_url = tdb.url();
+    let ns1 = NotifyServer::new(&db_url).await?;
+    let ns2 = NotifyServer::new(&db_url).await?;
+    let mut events1 = ns1.connect(&cs.token).await?;
+    let mut events2 = ns2.connect(&cs.token).await?;
+
+    cs.create_chat().await?;
+    let event1 = next_event(&mut events1, "NewChat").await?;
+    let event2 = next_event(&mut events2, "NewChat").await?;
+    assert!(!event1.id.is_empty());
+    assert_eq!(event1.id, event2.id);
+    assert_eq!(event1.data, event2.data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn presence_should_count_connections_of_all_instances() -> anyhow::Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let cs = ChatServer::new(state).await?;
+    let db_url = tdb.url();
+    let ns1 = NotifyServer::new(&db_url).await?;
+    let ns2 = NotifyServer::new(&db_url).await?;
+    let es1 = ns1.open(&cs.token).await?;
+    let es2 = ns2.open(&cs.token).await?;
+    cs.wait_presence(1, Presence::Online).await?;
+
+    // still connected to the first instance
+    drop(es2);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(cs.presence(1).await?, Presence::Online);
+
+    drop(es1);
+    cs.wait_presence(1, Presence::Away).await?;
     Ok(())
 }
 
@@ -64,7 +149,7 @@ impl ChatServer {
     async fn signin(&self) -> anyhow::Result<String> {
         let res = self
             .client
-            .post(&format!("http://{}/api/signin", self.addr))
+            .post(format!("http://{}/api/signin", self.addr))
             .header("Content-Type", "application/json")
             .body(r#"{"email": "hedon@acme.com", "password": "123456"}"#)
             .send()
@@ -79,7 +164,7 @@ impl ChatServer {
     async fn create_chat(&self) -> anyhow::Result<Chat> {
         let res = self
             .client
-            .post(&format!("http://{}/api/chats", self.addr))
+            .post(format!("http://{}/api/chats", self.addr))
             .header("Authorization", format!("Bearer {}", self.token))
             .header("Content-Type", "application/json")
             .body(r#"{"name": "test", "ws_id":1, "members": [1,2], "public": false}"#)
@@ -104,7 +189,7 @@ impl ChatServer {
 
         let res = self
             .client
-            .post(&format!("http://{}/api/upload", self.addr))
+            .post(format!("http://{}/api/upload", self.addr))
             .header("Authorization", format!("Bearer {}", self.token))
             .multipart(form)
             .send()
@@ -131,10 +216,91 @@ impl ChatServer {
         assert_eq!(msg.chat_id, chat_id as i64);
         Ok(msg)
     }
+
+    async fn update_message(&self, chat_id: u64, msg_id: u64) -> anyhow::Result<Message> {
+        let res = self
+            .client
+            .patch(format!(
+                "http://{}/api/chats/{}/messages/{}",
+                self.addr, chat_id, msg_id
+            ))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .header("Content-Type", "application/json")
+            .body(r#"{"content": "hello again"}"#)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let msg: Message = res.json().await?;
+        assert_eq!(msg.content, "hello again");
+        assert_eq!(msg.files.len(), 1);
+        assert!(msg.updated_at.is_some());
+        Ok(msg)
+    }
+
+    async fn delete_message(&self, chat_id: u64, msg_id: u64) -> anyhow::Result<Message> {
+        let res = self
+            .client
+            .delete(format!(
+                "http://{}/api/chats/{}/messages/{}",
+                self.addr, chat_id, msg_id
+            ))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let msg: Message = res.json().await?;
+        assert_eq!(msg.content, "");
+        assert!(msg.deleted_at.is_some());
+        Ok(msg)
+    }
+
+    async fn presence(&self, user_id: i64) -> anyhow::Result<Presence> {
+        let res = self
+            .client
+            .get(format!("http://{}/api/users", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let users: Vec<ChatUser> = res.json().await?;
+        let user = users
+            .into_iter()
+            .find(|u| u.id == user_id)
+            .ok_or_else(|| anyhow::anyhow!("user {} not found", user_id))?;
+        Ok(user.presence)
+    }
+
+    /// Wait for the notify servers to store the presence of the user.
+    async fn wait_presence(&self, user_id: i64, presence: Presence) -> anyhow::Result<()> {
+        for _ in 0..50 {
+            if self.presence(user_id).await? == presence {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        anyhow::bail!("user {} is not {:?}", user_id, presence)
+    }
+
+    async fn update_chat(&self, chat_id: u64) -> anyhow::Result<Chat> {
+        let res = self
+            .client
+            .patch(format!("http://{}/api/chats/{}", self.addr, chat_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .header("Content-Type", "application/json")
+            .body(r#"{"add_members": [3]}"#)
+            .send()
+            .await?;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let chat: Chat = res.json().await?;
+        assert_eq!(chat.name.as_ref().unwrap(), "test");
+        assert_eq!(chat.members, vec![1, 2, 3]);
+        Ok(chat)
+    }
 }
 
 impl NotifyServer {
-    async fn new(db_url: &str, token: &str) -> anyhow::Result<Self> {
+    async fn new(db_url: &str) -> anyhow::Result<Self> {
         let mut config = notify_server::AppConfig::load()?;
         config.server.db_url = db_url.to_string();
         let app = notify_server::get_router(config).await?;
@@ -147,30 +313,39 @@ impl NotifyServer {
                 .unwrap();
         });
 
-        let mut es = EventSource::get(format!("http://{}/events?token={}", addr, token));
+        Ok(Self { addr })
+    }
 
+    /// Open an event connection of the user, it's closed when dropped.
+    async fn open(&self, token: &str) -> anyhow::Result<EventSource> {
+        let mut es = EventSource::get(format!("http://{}/events?token={}", self.addr, token));
+        match es.next().await {
+            Some(Ok(Event::Open)) => println!("Connection Open!"),
+            event => anyhow::bail!("connection not open: {:?}", event),
+        }
+        Ok(es)
+    }
+
+    /// Connect to the events of the user, returns once the connection is open.
+    async fn connect(&self, token: &str) -> anyhow::Result<UnboundedReceiver<SseEvent>> {
+        let mut es = self.open(token).await?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
         tokio::spawn(async move {
             // NOTE: next() need `futures` crate
             while let Some(event) = es.next().await {
                 match event {
                     Ok(Event::Open) => println!("Connection Open!"),
-                    Ok(Event::Message(message)) => match message.event.as_str() {
-                        "NewChat" => {
-                            let chat: Chat = serde_json::from_str(&message.data).unwrap();
-                            assert_eq!(chat.name.as_ref().unwrap(), "test");
-                            assert_eq!(chat.members, vec![1, 2]);
-                            assert_eq!(chat.r#type, ChatType::PrivateChannel);
-                        }
-                        "NewMessage" => {
-                            let msg: Message = serde_json::from_str(&message.data).unwrap();
-                            assert_eq!(msg.content, "hello");
-                            assert_eq!(msg.files.len(), 1);
-                            assert_eq!(msg.sender_id, 1);
+                    Ok(Event::Message(message)) => {
+                        let event = SseEvent {
+                            event: message.event,
+                            id: message.id,
+                            data: message.data,
+                        };
+                        if tx.send(event).is_err() {
+                            es.close();
                         }
-                        _ => {
-                            panic!("unexpected event: {:?}", message);
-                        }
-                    },
+                    }
                     Err(err) => {
                         println!("Error: {}", err);
                         es.close();
@@ -178,6 +353,30 @@ impl NotifyServer {
                 }
             }
         });
-        Ok(Self)
+        Ok(rx)
     }
 }
+
+/// Wait for the next event with the name, skipping presence and the like.
+async fn next_event(
+    events: &mut UnboundedReceiver<SseEvent>,
+    name: &str,
+) -> anyhow::Result<SseEvent> {
+    loop {
+        let message = timeout(Duration::from_secs(5), events.recv())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("event stream closed"))?;
+        match message.event.as_str() {
+            "PresenceChanged" | "ReadReceipt" => continue,
+            event if event == name => return Ok(message),
+            _ => anyhow::bail!("unexpected event: {:?}", message),
+        }
+    }
+}
+
+/// The `type` tag of a chat event comes before the type of the chat, so parse it as a map where
+/// the later field wins.
+fn parse_chat(data: &str) -> anyhow::Result<Chat> {
+    let value: serde_json::Value = serde_json::from_str(data)?;
+    Ok(serde_json::from_value(value)?)
+}
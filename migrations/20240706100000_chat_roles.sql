@@ -0,0 +1,30 @@
+-- Add migration script here
+-- create chat role: owner, admin, member
+CREATE TYPE chat_role AS ENUM(
+  'owner',
+  'admin',
+  'member'
+);
+
+-- create chat roles table, members without a row here are plain members
+CREATE TABLE IF NOT EXISTS chat_roles (
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  role chat_role NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+-- a chat has exactly one owner
+CREATE UNIQUE INDEX IF NOT EXISTS chat_roles_owner_index ON chat_roles(chat_id) WHERE role = 'owner';
+
+-- existing chats don't know their creator, make the first member the owner
+INSERT INTO chat_roles(chat_id, user_id, role)
+SELECT
+  id,
+  members[1],
+  'owner'
+FROM
+  chats
+WHERE
+  array_length(members, 1) > 0;
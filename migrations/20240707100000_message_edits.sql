@@ -0,0 +1,56 @@
+-- Add migration script here
+-- edited and deleted messages are kept as tombstones
+ALTER TABLE messages
+  ADD COLUMN updated_at timestamptz,
+  ADD COLUMN deleted_at timestamptz;
+
+-- create message edit history table, keep the content before each edit
+CREATE TABLE IF NOT EXISTS message_edits (
+  id bigserial PRIMARY KEY,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  files text[] DEFAULT '{}',
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for message edits for message_id
+CREATE INDEX IF NOT EXISTS message_edits_message_id_index ON message_edits(message_id, id DESC);
+
+-- if message added, updated or deleted, notify with message data
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_message: %', NEW;
+  -- select chat with chat_id in NEW
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+  ELSIF TG_OP = 'UPDATE' THEN
+    IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_deleted', json_build_object('message', NEW, 'members', USERS)::text);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS add_to_message_trigger ON messages;
+
+CREATE TRIGGER add_to_message_trigger
+  AFTER INSERT OR UPDATE ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_message();
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- reply_to is the message being replied to, thread_root_id is the top level message of the thread
+ALTER TABLE messages
+  ADD COLUMN reply_to bigint REFERENCES messages(id) ON DELETE SET NULL,
+  ADD COLUMN thread_root_id bigint REFERENCES messages(id) ON DELETE CASCADE;
+
+-- create index for message for thread_root_id
+CREATE INDEX IF NOT EXISTS thread_root_id_index ON messages(thread_root_id, id DESC) WHERE thread_root_id IS NOT NULL;
@@ -0,0 +1,50 @@
+-- Add migration script here
+-- create message reactions table, one row per (message, user, emoji)
+CREATE TABLE IF NOT EXISTS message_reactions (
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  emoji varchar(64) NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id, emoji)
+);
+
+-- if reaction added or removed, notify with reaction data
+CREATE OR REPLACE FUNCTION add_to_message_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  REACTION message_reactions;
+  CHAT_ID bigint;
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    REACTION := OLD;
+  ELSE
+    REACTION := NEW;
+  END IF;
+  RAISE NOTICE 'add_to_message_reaction: %', REACTION;
+  -- select chat of the message in REACTION
+  SELECT
+    c.id,
+    c.members INTO CHAT_ID,
+    USERS
+  FROM
+    messages m
+    JOIN chats c ON c.id = m.chat_id
+  WHERE
+    m.id = REACTION.message_id;
+  -- the whole chat is being deleted
+  IF CHAT_ID IS NULL THEN
+    RETURN NULL;
+  END IF;
+  PERFORM
+    pg_notify('message_reaction_changed', json_build_object('op', TG_OP, 'chat_id', CHAT_ID, 'reaction', REACTION, 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_message_reaction_trigger
+  AFTER INSERT OR DELETE ON message_reactions
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_message_reaction();
@@ -0,0 +1,36 @@
+-- Add migration script here
+-- create read receipts table, the last message read by the user in the chat
+CREATE TABLE IF NOT EXISTS read_receipts (
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  last_read_id bigint NOT NULL,
+  updated_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+-- if read receipt changed, notify with receipt data
+CREATE OR REPLACE FUNCTION add_to_read_receipt()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_read_receipt: %', NEW;
+  -- select chat with chat_id in NEW
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  PERFORM
+    pg_notify('read_receipt_updated', json_build_object('receipt', NEW, 'members', USERS)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_read_receipt_trigger
+  AFTER INSERT OR UPDATE ON read_receipts
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_read_receipt();
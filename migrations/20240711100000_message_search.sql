@@ -0,0 +1,7 @@
+-- Add migration script here
+-- full text search for message content, `simple` config since chats are in any language
+ALTER TABLE messages
+  ADD COLUMN content_tsv tsvector GENERATED ALWAYS AS (to_tsvector('simple', content)) STORED;
+
+-- create index for message for content_tsv
+CREATE INDEX IF NOT EXISTS content_tsv_index ON messages USING GIN(content_tsv);
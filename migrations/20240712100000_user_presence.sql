@@ -0,0 +1,40 @@
+-- Add migration script here
+CREATE TYPE presence_status AS ENUM(
+  'online',
+  'away',
+  'offline'
+);
+
+-- create user presence table, maintained by notify server from the active event connections
+CREATE TABLE IF NOT EXISTS user_presence (
+  user_id bigint PRIMARY KEY REFERENCES users(id),
+  status presence_status NOT NULL DEFAULT 'offline',
+  updated_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- if presence changed, notify with presence data to all users of the workspace
+CREATE OR REPLACE FUNCTION add_to_user_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_user_presence: %', NEW;
+  -- select users in the same workspace as user_id in NEW
+  SELECT
+    array_agg(id) INTO USERS
+  FROM
+    users
+  WHERE
+    ws_id = (SELECT ws_id FROM users WHERE id = NEW.user_id);
+  PERFORM
+    pg_notify('user_presence_changed', json_build_object('user_id', NEW.user_id, 'status', NEW.status, 'members', USERS)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_user_presence_trigger
+  AFTER INSERT OR UPDATE ON user_presence
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_user_presence();
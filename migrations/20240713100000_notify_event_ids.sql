@@ -0,0 +1,126 @@
+-- Add migration script here
+-- every notification carries a global event id, so all notify server instances agree on the id
+-- of an event: clients can resume on any instance, and instances can drop duplicated events
+CREATE SEQUENCE IF NOT EXISTS notify_event_id_seq;
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('event_id', nextval('notify_event_id_seq'), 'op', TG_OP, 'old', OLD, 'new', NEW)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PAYLOAD text;
+BEGIN
+  RAISE NOTICE 'add_to_message: %', NEW;
+  -- select chat with chat_id in NEW
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  PAYLOAD := json_build_object('event_id', nextval('notify_event_id_seq'), 'message', NEW, 'members', USERS)::text;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_message_created', PAYLOAD);
+  ELSIF TG_OP = 'UPDATE' THEN
+    IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_deleted', PAYLOAD);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', PAYLOAD);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  REACTION message_reactions;
+  CHAT_ID bigint;
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    REACTION := OLD;
+  ELSE
+    REACTION := NEW;
+  END IF;
+  RAISE NOTICE 'add_to_message_reaction: %', REACTION;
+  -- select chat of the message in REACTION
+  SELECT
+    c.id,
+    c.members INTO CHAT_ID,
+    USERS
+  FROM
+    messages m
+    JOIN chats c ON c.id = m.chat_id
+  WHERE
+    m.id = REACTION.message_id;
+  -- the whole chat is being deleted
+  IF CHAT_ID IS NULL THEN
+    RETURN NULL;
+  END IF;
+  PERFORM
+    pg_notify('message_reaction_changed', json_build_object('event_id', nextval('notify_event_id_seq'), 'op', TG_OP, 'chat_id', CHAT_ID, 'reaction', REACTION, 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_read_receipt()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_read_receipt: %', NEW;
+  -- select chat with chat_id in NEW
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  PERFORM
+    pg_notify('read_receipt_updated', json_build_object('event_id', nextval('notify_event_id_seq'), 'receipt', NEW, 'members', USERS)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_user_presence()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  RAISE NOTICE 'add_to_user_presence: %', NEW;
+  -- select users in the same workspace as user_id in NEW
+  SELECT
+    array_agg(id) INTO USERS
+  FROM
+    users
+  WHERE
+    ws_id = (SELECT ws_id FROM users WHERE id = NEW.user_id);
+  PERFORM
+    pg_notify('user_presence_changed', json_build_object('event_id', nextval('notify_event_id_seq'), 'user_id', NEW.user_id, 'status', NEW.status, 'members', USERS)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,122 @@
+-- Add migration script here
+-- pg_notify payloads are limited to 8000 bytes, so they only carry ids and other fixed size
+-- fields: notify server loads the rows and the chat members by itself
+-- a deleted chat can't be loaded anymore, keep the old row of the chat for a while instead
+CREATE TABLE IF NOT EXISTS notify_chat_snapshots(
+  event_id bigint PRIMARY KEY,
+  chat jsonb NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS notify_chat_snapshots_created_at_idx ON notify_chat_snapshots(created_at);
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  EVENT_ID bigint;
+  CHAT_ID bigint;
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  -- only member changes are sent to the users
+  IF TG_OP = 'UPDATE' AND OLD.members = NEW.members THEN
+    RETURN NEW;
+  END IF;
+  EVENT_ID := nextval('notify_event_id_seq');
+  IF TG_OP = 'INSERT' THEN
+    CHAT_ID := NEW.id;
+  ELSE
+    CHAT_ID := OLD.id;
+    -- removed members have to be notified too
+    INSERT INTO notify_chat_snapshots(event_id, chat)
+      VALUES (EVENT_ID, to_jsonb(OLD));
+    DELETE FROM notify_chat_snapshots
+    WHERE created_at < CURRENT_TIMESTAMP - INTERVAL '1 hour';
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('event_id', EVENT_ID, 'op', TG_OP, 'chat_id', CHAT_ID)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  PAYLOAD text;
+BEGIN
+  RAISE NOTICE 'add_to_message: %', NEW;
+  PAYLOAD := json_build_object('event_id', nextval('notify_event_id_seq'), 'chat_id', NEW.chat_id, 'message_id', NEW.id)::text;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_message_created', PAYLOAD);
+  ELSIF TG_OP = 'UPDATE' THEN
+    IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_deleted', PAYLOAD);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', PAYLOAD);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  REACTION message_reactions;
+  CHAT_ID bigint;
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    REACTION := OLD;
+  ELSE
+    REACTION := NEW;
+  END IF;
+  RAISE NOTICE 'add_to_message_reaction: %', REACTION;
+  -- select chat of the message in REACTION
+  SELECT
+    m.chat_id INTO CHAT_ID
+  FROM
+    messages m
+  WHERE
+    m.id = REACTION.message_id;
+  -- the whole chat is being deleted
+  IF CHAT_ID IS NULL THEN
+    RETURN NULL;
+  END IF;
+  -- emojis are short, the reaction row is small enough for the payload
+  PERFORM
+    pg_notify('message_reaction_changed', json_build_object('event_id', nextval('notify_event_id_seq'), 'op', TG_OP, 'chat_id', CHAT_ID, 'reaction', REACTION)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_read_receipt()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'add_to_read_receipt: %', NEW;
+  PERFORM
+    pg_notify('read_receipt_updated', json_build_object('event_id', nextval('notify_event_id_seq'), 'receipt', NEW)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_user_presence()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'add_to_user_presence: %', NEW;
+  PERFORM
+    pg_notify('user_presence_changed', json_build_object('event_id', nextval('notify_event_id_seq'), 'user_id', NEW.user_id, 'status', NEW.status)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
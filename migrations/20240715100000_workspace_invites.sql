@@ -0,0 +1,18 @@
+-- Add migration script here
+-- users join an existing workspace with an invite from its owner
+CREATE TABLE IF NOT EXISTS workspace_invites(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  token varchar(64) NOT NULL UNIQUE,
+  -- only this email can sign up with the invite if set
+  email varchar(64),
+  created_by bigint NOT NULL REFERENCES users(id),
+  expires_at timestamptz,
+  -- a reusable invite works like a link shared with the team
+  reusable boolean NOT NULL DEFAULT FALSE,
+  uses integer NOT NULL DEFAULT 0,
+  revoked_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS workspace_invites_ws_id_idx ON workspace_invites(ws_id, id DESC);
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- deactivated users can't sign in, and their tokens are rejected
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS deactivated_at timestamptz;
+
+-- workspace settings, changed by the owner
+ALTER TABLE workspaces
+  ADD COLUMN IF NOT EXISTS public_channels boolean NOT NULL DEFAULT TRUE,
+  ADD COLUMN IF NOT EXISTS invite_expires_in bigint;
@@ -0,0 +1,25 @@
+-- Add migration script here
+-- a user can be a member of several workspaces, users.ws_id is the workspace to sign in to
+CREATE TABLE IF NOT EXISTS workspace_members(
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- the owner of the workspace deactivates a member in the workspace only
+  deactivated_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_idx ON workspace_members(user_id);
+
+INSERT INTO workspace_members(ws_id, user_id, deactivated_at)
+SELECT
+  ws_id,
+  id,
+  deactivated_at
+FROM
+  users
+ON CONFLICT (ws_id, user_id)
+  DO NOTHING;
+
+ALTER TABLE users
+  DROP COLUMN IF EXISTS deactivated_at;
@@ -0,0 +1,25 @@
+-- Add migration script here
+-- a signin starts a session, its access tokens carry the session id and are short-lived
+CREATE TABLE IF NOT EXISTS sessions(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- workspace the access tokens of the session are scoped to
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  -- extended on every refresh
+  expires_at timestamptz NOT NULL,
+  revoked_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions(user_id);
+
+-- refresh tokens rotate on every use, the used ones are kept to detect a replay
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+  -- sha256 of the token, the token itself is only known by the client
+  token_hash text PRIMARY KEY,
+  session_id bigint NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_session_id_idx ON refresh_tokens(session_id);
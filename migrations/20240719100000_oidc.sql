@@ -0,0 +1,25 @@
+-- Add migration script here
+-- users signing in with OIDC only have no password
+ALTER TABLE users
+  ALTER COLUMN password_hash DROP NOT NULL;
+
+-- accounts at OIDC providers linked to users
+CREATE TABLE IF NOT EXISTS user_identities(
+  issuer varchar(256) NOT NULL,
+  -- `sub` claim, stable for the account at the issuer unlike its email
+  subject varchar(256) NOT NULL,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON user_identities(user_id);
+
+-- logins redirected to the OIDC provider and not back yet
+CREATE TABLE IF NOT EXISTS oidc_logins(
+  state varchar(64) PRIMARY KEY,
+  nonce varchar(64) NOT NULL,
+  -- PKCE verifier of the code challenge sent to the provider
+  code_verifier varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,27 @@
+-- Add migration script here
+-- users who proved to own their email, by a mailed token or the OIDC provider
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS email_verified_at timestamptz;
+
+-- members have to verify their email before they can sign in
+ALTER TABLE workspaces
+  ADD COLUMN IF NOT EXISTS require_verified_email boolean NOT NULL DEFAULT FALSE;
+
+CREATE TYPE email_token_purpose AS ENUM(
+  'verify_email',
+  'reset_password'
+);
+
+-- one-time tokens mailed to users, the signed token carries the id
+CREATE TABLE IF NOT EXISTS email_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  purpose email_token_purpose NOT NULL,
+  -- the email the token was sent to, it's invalid once the user changes it
+  email varchar(64) NOT NULL,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS email_tokens_user_id_idx ON email_tokens(user_id);
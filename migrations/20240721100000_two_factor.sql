@@ -0,0 +1,32 @@
+-- Add migration script here
+-- TOTP secret in base32, pending until the user enables it with a code
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS totp_secret varchar(64),
+  ADD COLUMN IF NOT EXISTS totp_enabled_at timestamptz,
+  -- time step of the last code used, a code can't be used twice
+  ADD COLUMN IF NOT EXISTS totp_last_step bigint NOT NULL DEFAULT 0;
+
+-- members have to use two-factor authentication to sign in
+ALTER TABLE workspaces
+  ADD COLUMN IF NOT EXISTS require_two_factor boolean NOT NULL DEFAULT FALSE;
+
+-- one-time codes to sign in with when the authenticator is lost
+CREATE TABLE IF NOT EXISTS recovery_codes(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  code_hash varchar(128) NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id_idx ON recovery_codes(user_id);
+
+-- signins waiting for the second factor, the challenge token carries the id
+CREATE TABLE IF NOT EXISTS two_factor_challenges(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  attempts int NOT NULL DEFAULT 0,
+  expires_at timestamptz NOT NULL,
+  used_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
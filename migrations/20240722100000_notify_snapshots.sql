@@ -0,0 +1,84 @@
+-- Add migration script here
+-- the rows may change again before notify server loads them, so chat and message events keep
+-- the rows as they were at the change under the event id, not only the old rows of chats
+ALTER TABLE notify_chat_snapshots RENAME TO notify_snapshots;
+
+ALTER INDEX notify_chat_snapshots_created_at_idx RENAME TO notify_snapshots_created_at_idx;
+
+ALTER TABLE notify_snapshots RENAME COLUMN chat TO old;
+
+ALTER TABLE notify_snapshots
+  ALTER COLUMN old DROP NOT NULL,
+  ADD COLUMN new jsonb;
+
+CREATE OR REPLACE FUNCTION add_notify_snapshot(event_id bigint, old jsonb, new jsonb)
+  RETURNS void
+  AS $$
+BEGIN
+  INSERT INTO notify_snapshots(event_id, old, new)
+    VALUES (event_id, old, new);
+  DELETE FROM notify_snapshots
+  WHERE created_at < CURRENT_TIMESTAMP - INTERVAL '1 hour';
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  EVENT_ID bigint;
+  CHAT_ID bigint;
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  EVENT_ID := nextval('notify_event_id_seq');
+  IF TG_OP = 'INSERT' THEN
+    CHAT_ID := NEW.id;
+    PERFORM
+      add_notify_snapshot(EVENT_ID, NULL, to_jsonb(NEW));
+  ELSIF TG_OP = 'UPDATE' THEN
+    CHAT_ID := NEW.id;
+    PERFORM
+      add_notify_snapshot(EVENT_ID, to_jsonb(OLD), to_jsonb(NEW));
+  ELSE
+    -- removed members have to be notified too
+    CHAT_ID := OLD.id;
+    PERFORM
+      add_notify_snapshot(EVENT_ID, to_jsonb(OLD), NULL);
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('event_id', EVENT_ID, 'op', TG_OP, 'chat_id', CHAT_ID)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  EVENT_ID bigint;
+  PAYLOAD text;
+BEGIN
+  RAISE NOTICE 'add_to_message: %', NEW;
+  EVENT_ID := nextval('notify_event_id_seq');
+  -- the search vector is not part of a message
+  PERFORM
+    add_notify_snapshot(EVENT_ID, NULL, to_jsonb(NEW) - 'content_tsv');
+  PAYLOAD := json_build_object('event_id', EVENT_ID, 'chat_id', NEW.chat_id, 'message_id', NEW.id)::text;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_message_created', PAYLOAD);
+  ELSIF TG_OP = 'UPDATE' THEN
+    IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_deleted', PAYLOAD);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', PAYLOAD);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,17 @@
+-- Add migration script here
+-- notify server inserts the presence row of a user as offline to lock it before changing it,
+-- a missing row is offline already so the insert doesn't change the presence
+CREATE OR REPLACE FUNCTION add_to_user_presence()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  RAISE NOTICE 'add_to_user_presence: %', NEW;
+  IF TG_OP = 'INSERT' AND NEW.status = 'offline' THEN
+    RETURN NEW;
+  END IF;
+  PERFORM
+    pg_notify('user_presence_changed', json_build_object('event_id', nextval('notify_event_id_seq'), 'user_id', NEW.user_id, 'status', NEW.status)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
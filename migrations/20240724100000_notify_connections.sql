@@ -0,0 +1,18 @@
+-- Add migration script here
+-- notify server instances, refreshed by each instance while it runs
+CREATE TABLE IF NOT EXISTS notify_instances(
+  id bigserial PRIMARY KEY,
+  seen_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- event connections of the users on each instance, the presence of a user is derived from
+-- the rows of all instances. A row without connections is kept while the instance keeps the
+-- event channel of the user for a reconnect
+CREATE TABLE IF NOT EXISTS notify_connections(
+  instance_id bigint NOT NULL REFERENCES notify_instances(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  connections integer NOT NULL,
+  PRIMARY KEY (user_id, instance_id)
+);
+
+CREATE INDEX IF NOT EXISTS notify_connections_instance_id_idx ON notify_connections(instance_id);
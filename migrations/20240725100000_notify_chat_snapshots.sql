@@ -0,0 +1,71 @@
+-- Add migration script here
+-- notify server loads the chats and messages by id, only the old row of a chat is kept for the
+-- members an update or a delete removed. Notify server prunes the snapshots it doesn't need
+-- anymore, outside of the writes of the users
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  EVENT_ID bigint;
+  CHAT_ID bigint;
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  EVENT_ID := nextval('notify_event_id_seq');
+  IF TG_OP = 'INSERT' THEN
+    CHAT_ID := NEW.id;
+  ELSE
+    CHAT_ID := OLD.id;
+    -- removed members have to be notified too
+    IF TG_OP = 'DELETE' OR NOT OLD.members <@ NEW.members THEN
+      INSERT INTO notify_chat_snapshots(event_id, chat)
+        VALUES (EVENT_ID, to_jsonb(OLD));
+    END IF;
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('event_id', EVENT_ID, 'op', TG_OP, 'chat_id', CHAT_ID)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  PAYLOAD text;
+BEGIN
+  RAISE NOTICE 'add_to_message: %', NEW;
+  PAYLOAD := json_build_object('event_id', nextval('notify_event_id_seq'), 'chat_id', NEW.chat_id, 'message_id', NEW.id)::text;
+  IF TG_OP = 'INSERT' THEN
+    PERFORM
+      pg_notify('chat_message_created', PAYLOAD);
+  ELSIF TG_OP = 'UPDATE' THEN
+    IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+      PERFORM
+        pg_notify('chat_message_deleted', PAYLOAD);
+    ELSE
+      PERFORM
+        pg_notify('chat_message_updated', PAYLOAD);
+    END IF;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+DROP FUNCTION IF EXISTS add_notify_snapshot(bigint, jsonb, jsonb);
+
+DELETE FROM notify_snapshots
+WHERE old IS NULL;
+
+ALTER TABLE notify_snapshots
+  DROP COLUMN new;
+
+ALTER TABLE notify_snapshots RENAME COLUMN old TO chat;
+
+ALTER TABLE notify_snapshots
+  ALTER COLUMN chat SET NOT NULL;
+
+ALTER TABLE notify_snapshots RENAME TO notify_chat_snapshots;
+
+ALTER INDEX notify_snapshots_created_at_idx RENAME TO notify_chat_snapshots_created_at_idx;
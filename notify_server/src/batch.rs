@@ -0,0 +1,187 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    time::Duration,
+};
+
+use chat_core::{Chat, Message};
+use sqlx::{FromRow, PgPool};
+use tracing::warn;
+
+use crate::AppState;
+
+/// Old rows of chats are only needed until the notifications are handled.
+const SNAPSHOT_TTL: Duration = Duration::from_secs(60 * 60);
+const SNAPSHOT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+/// Ids referred by a batch of notifications, each table is queried once for the whole batch.
+#[derive(Debug, Default)]
+pub(crate) struct Batch {
+    pub(crate) chat_ids: HashSet<i64>,
+    /// event ids of the chat snapshots
+    pub(crate) snapshot_ids: HashSet<i64>,
+    pub(crate) message_ids: HashSet<i64>,
+    /// users whose workspace members are needed
+    pub(crate) user_ids: HashSet<i64>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Rows {
+    pub(crate) chats: HashMap<i64, ChatRow>,
+    /// old rows of deleted chats and of chats members were removed from, by event id
+    pub(crate) snapshots: HashMap<i64, Chat>,
+    pub(crate) messages: HashMap<i64, Message>,
+    /// members of the workspace of each user
+    pub(crate) ws_members: HashMap<i64, Vec<i64>>,
+}
+
+/// A chat and its members who are still active in its workspace, only they get its events.
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct ChatRow {
+    #[sqlx(flatten)]
+    pub(crate) chat: Chat,
+    pub(crate) active_members: Vec<i64>,
+}
+
+impl Batch {
+    pub(crate) async fn load(self, state: &AppState) -> Result<Rows, sqlx::Error> {
+        let (chats, snapshots, messages, ws_members) = tokio::try_join!(
+            fetch_rows(state, self.chat_ids, fetch_chats),
+            fetch_rows(state, self.snapshot_ids, fetch_snapshots),
+            fetch_rows(state, self.message_ids, fetch_messages),
+            fetch_rows(state, self.user_ids, fetch_ws_members),
+        )?;
+        Ok(Rows {
+            chats,
+            snapshots,
+            messages,
+            ws_members,
+        })
+    }
+}
+
+/// Delete the old rows of chats kept by the triggers once they are not needed anymore. Every
+/// instance does it, the database triggers are left with the inserts.
+pub(crate) async fn prune_snapshots(state: AppState) {
+    let mut interval = tokio::time::interval(SNAPSHOT_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM notify_chat_snapshots
+            WHERE created_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+            "#,
+        )
+        .bind(SNAPSHOT_TTL.as_secs_f64())
+        .execute(&state.pool)
+        .await;
+        if let Err(e) = ret {
+            warn!("Failed to prune chat snapshots: {}", e);
+        }
+    }
+}
+
+/// Fetch the rows from the read pool. A replica may lag behind the notification, so the rows it
+/// doesn't have yet are fetched from the primary.
+async fn fetch_rows<T, F, Fut>(
+    state: &AppState,
+    ids: HashSet<i64>,
+    fetch: F,
+) -> Result<HashMap<i64, T>, sqlx::Error>
+where
+    F: Fn(PgPool, Vec<i64>) -> Fut,
+    Fut: Future<Output = Result<HashMap<i64, T>, sqlx::Error>>,
+{
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut rows = fetch(state.read_pool.clone(), ids.iter().copied().collect()).await?;
+    if state.config.server.read_db_url.is_some() {
+        let missing: Vec<i64> = ids
+            .into_iter()
+            .filter(|id| !rows.contains_key(id))
+            .collect();
+        if !missing.is_empty() {
+            rows.extend(fetch(state.pool.clone(), missing).await?);
+        }
+    }
+    Ok(rows)
+}
+
+async fn fetch_chats(pool: PgPool, ids: Vec<i64>) -> Result<HashMap<i64, ChatRow>, sqlx::Error> {
+    let chats: Vec<ChatRow> = sqlx::query_as(
+        r#"
+        SELECT c.id, c.ws_id, c.name, c.type, c.members, c.created_at,
+            ARRAY(
+                SELECT m.user_id
+                FROM workspace_members m
+                WHERE m.ws_id = c.ws_id AND m.user_id = ANY(c.members)
+                    AND m.deactivated_at IS NULL
+                ORDER BY m.user_id
+            ) AS active_members
+        FROM chats c
+        WHERE c.id = ANY($1)
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(chats.into_iter().map(|row| (row.chat.id, row)).collect())
+}
+
+async fn fetch_snapshots(pool: PgPool, ids: Vec<i64>) -> Result<HashMap<i64, Chat>, sqlx::Error> {
+    let rows: Vec<(i64, String)> = sqlx::query_as(
+        r#"
+        SELECT event_id, chat::text
+        FROM notify_chat_snapshots
+        WHERE event_id = ANY($1)
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(&pool)
+    .await?;
+
+    rows.into_iter()
+        .map(|(event_id, chat)| {
+            let chat = serde_json::from_str(&chat).map_err(|e| sqlx::Error::Decode(e.into()))?;
+            Ok((event_id, chat))
+        })
+        .collect()
+}
+
+async fn fetch_messages(pool: PgPool, ids: Vec<i64>) -> Result<HashMap<i64, Message>, sqlx::Error> {
+    let messages: Vec<Message> = sqlx::query_as(
+        r#"
+        SELECT id, chat_id, sender_id, content, files, created_at, updated_at, deleted_at,
+            reply_to, thread_root_id
+        FROM messages
+        WHERE id = ANY($1)
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(messages.into_iter().map(|msg| (msg.id, msg)).collect())
+}
+
+async fn fetch_ws_members(
+    pool: PgPool,
+    ids: Vec<i64>,
+) -> Result<HashMap<i64, Vec<i64>>, sqlx::Error> {
+    let rows: Vec<(i64, Vec<i64>)> = sqlx::query_as(
+        r#"
+        SELECT u.user_id, array_agg(DISTINCT o.user_id ORDER BY o.user_id)
+        FROM workspace_members u
+        JOIN workspace_members o ON o.ws_id = u.ws_id
+        WHERE u.user_id = ANY($1)
+        GROUP BY u.user_id
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows.into_iter().collect())
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast;
+
+use crate::AppEvent;
+
+const CHANNEL_CAPACITY: usize = 1024;
+/// Number of recent events kept for each user to replay to a reconnecting client.
+const REPLAY_CAPACITY: usize = 256;
+
+/// An event to a user. Ids are global and shared by all instances, but follow the commit order of
+/// the database transactions, so they are not sorted: a client resumes after the position of the
+/// last event it got.
+#[derive(Debug, Clone)]
+pub struct UserEvent {
+    pub id: u64,
+    pub event: Arc<AppEvent>,
+}
+
+/// The events of a user, shared by all connections of the user.
+#[derive(Debug)]
+pub struct UserChannel {
+    tx: broadcast::Sender<UserEvent>,
+    replay: Mutex<ReplayBuffer>,
+}
+
+pub(crate) enum Replay {
+    /// Events missed since the last event id, oldest first
+    Events(Vec<UserEvent>),
+    /// Missed events are not in the buffer, the client has to resync
+    Resync(UserEvent),
+}
+
+#[derive(Debug)]
+struct ReplayBuffer {
+    events: VecDeque<UserEvent>,
+    /// id of the last event evicted, all events after it are in the buffer
+    evicted: Option<u64>,
+}
+
+impl UserChannel {
+    pub(crate) fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let replay = ReplayBuffer {
+            events: VecDeque::with_capacity(REPLAY_CAPACITY),
+            evicted: None,
+        };
+        Self {
+            tx,
+            replay: Mutex::new(replay),
+        }
+    }
+
+    /// Keep the event for replay and send it to the live connections.
+    pub(crate) fn send(&self, event: UserEvent) {
+        let mut replay = self.replay.lock().expect("replay lock poisoned");
+        if replay.events.len() == REPLAY_CAPACITY {
+            replay.evicted = replay.events.pop_front().map(|e| e.id);
+        }
+        replay.events.push_back(event.clone());
+        // no live connection is fine, the event waits in the buffer
+        let _ = self.tx.send(event);
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<UserEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Subscribe to live events and get the ones missed after `last_event_id`. Both are taken under
+    /// the same lock as sending, so no event is lost or duplicated in between.
+    pub(crate) fn resume(&self, last_event_id: u64) -> (broadcast::Receiver<UserEvent>, Replay) {
+        let replay = self.replay.lock().expect("replay lock poisoned");
+        let rx = self.tx.subscribe();
+        let start = match replay.events.iter().position(|e| e.id == last_event_id) {
+            Some(pos) => pos + 1,
+            None if replay.evicted == Some(last_event_id) => 0,
+            None => {
+                // the client resumes from the latest event after it resyncs
+                let event = UserEvent {
+                    id: replay.events.back().map(|e| e.id).unwrap_or(0),
+                    event: Arc::new(AppEvent::Resync),
+                };
+                return (rx, Replay::Resync(event));
+            }
+        };
+
+        let events = replay.events.iter().skip(start).cloned().collect();
+        (rx, Replay::Events(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u64) -> UserEvent {
+        UserEvent {
+            id,
+            event: Arc::new(AppEvent::Resync),
+        }
+    }
+
+    #[test]
+    fn resume_should_replay_missed_events() {
+        let channel = UserChannel::new();
+        // ids follow the commit order, not the id order
+        for id in [101, 103, 102, 104, 105] {
+            channel.send(event(id));
+        }
+
+        let (_rx, replay) = channel.resume(103);
+        let Replay::Events(events) = replay else {
+            panic!("should replay events");
+        };
+        let ids: Vec<_> = events.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![102, 104, 105]);
+
+        // unknown ids need a resync
+        let (_rx, replay) = channel.resume(99);
+        assert!(matches!(replay, Replay::Resync(e) if e.id == 105));
+        let (_rx, replay) = channel.resume(106);
+        assert!(matches!(replay, Replay::Resync(_)));
+    }
+
+    #[test]
+    fn resume_should_resync_when_events_are_evicted() {
+        let channel = UserChannel::new();
+        for id in 1..=(REPLAY_CAPACITY as u64 + 10) {
+            channel.send(event(id));
+        }
+
+        let (_rx, replay) = channel.resume(5);
+        assert!(matches!(replay, Replay::Resync(_)));
+        let (_rx, replay) = channel.resume(10);
+        assert!(matches!(replay, Replay::Events(events) if events.len() == REPLAY_CAPACITY));
+    }
+}
@@ -13,23 +13,38 @@ pub struct AppConfig {
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// Replica to load the rows of the notifications from, the primary if not set
+    #[serde(default)]
+    pub read_db_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub pk: String,
+    /// Other public keys to accept tokens of, e.g. the previous key until its tokens expire,
+    /// or the next one before chat_server signs with it
+    #[serde(default)]
+    pub accepted_pks: Vec<String>,
+}
+
+impl AuthConfig {
+    /// All public keys to accept tokens of, the current one first.
+    pub fn pks(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.pk.as_str()).chain(self.accepted_pks.iter().map(String::as_str))
+    }
 }
 
 impl AppConfig {
+    /// Load the config from `NOTIFY_CONFIG` if set, so several instances can run from one place.
     pub fn load() -> anyhow::Result<Self> {
         let ret = match (
+            env::var("NOTIFY_CONFIG"),
             File::open("notify.yml"),
             File::open("/etc/config/notify.yml"),
-            env::var("NOTIFY_CONFIG"),
         ) {
-            (Ok(reader), _, _) => serde_yaml::from_reader(reader),
+            (Ok(path), _, _) => serde_yaml::from_reader(File::open(path)?),
             (_, Ok(reader), _) => serde_yaml::from_reader(reader),
-            (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
+            (_, _, Ok(reader)) => serde_yaml::from_reader(reader),
             _ => bail!("Config file not found"),
         };
         Ok(ret?)
@@ -0,0 +1,154 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axum::{extract::State, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::AppState;
+
+/// How long a user stays away after the last connection is gone before going offline. The event
+/// channel of the user is kept that long as well, so a reconnecting client can resume.
+const AWAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Sse,
+    Ws,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TransportCounters {
+    sse: AtomicUsize,
+    ws: AtomicUsize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionMetrics {
+    /// users with an event channel, including the ones just disconnected
+    pub users: usize,
+    pub connected_users: usize,
+    pub connections: usize,
+    pub sse_connections: usize,
+    pub ws_connections: usize,
+}
+
+/// Tracks an event connection of a user, the user is online while any of them is alive.
+pub(crate) struct ConnectionGuard {
+    state: AppState,
+    user_id: u64,
+    transport: Transport,
+}
+
+impl ConnectionGuard {
+    pub(crate) async fn connect(state: AppState, user_id: u64, transport: Transport) -> Self {
+        let count = {
+            let mut count = state.connections.entry(user_id).or_insert(0);
+            *count += 1;
+            *count
+        };
+        state
+            .transports
+            .get(transport)
+            .fetch_add(1, Ordering::Relaxed);
+        info!(
+            "User {} connected over {:?}, {} connections",
+            user_id, transport, count
+        );
+        if count == 1 {
+            state.sync_presence(user_id).await;
+        }
+        Self {
+            state,
+            user_id,
+            transport,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let user_id = self.user_id;
+        let count = match self.state.connections.get_mut(&user_id) {
+            Some(mut count) => {
+                *count -= 1;
+                *count
+            }
+            None => 0,
+        };
+        self.state
+            .transports
+            .get(self.transport)
+            .fetch_sub(1, Ordering::Relaxed);
+        info!(
+            "User {} disconnected from {:?}, {} connections",
+            user_id, self.transport, count
+        );
+        if count > 0 {
+            return;
+        }
+
+        // away, then offline once the channel is dropped, unless the user reconnects
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            state.sync_presence(user_id).await;
+            tokio::time::sleep(AWAY_TIMEOUT).await;
+            if state.remove_user(user_id) {
+                state.sync_presence(user_id).await;
+            }
+        });
+    }
+}
+
+impl TransportCounters {
+    fn get(&self, transport: Transport) -> &AtomicUsize {
+        match transport {
+            Transport::Sse => &self.sse,
+            Transport::Ws => &self.ws,
+        }
+    }
+}
+
+impl AppState {
+    /// Drop the event channel of the user if there is no connection any more.
+    fn remove_user(&self, user_id: u64) -> bool {
+        // a new connection is counted before it subscribes, so it either keeps the channel or
+        // creates a new one
+        let removed = self
+            .users
+            .remove_if(&user_id, |_, _| self.connection_count(user_id) == 0)
+            .is_some();
+        if removed {
+            self.connections.remove_if(&user_id, |_, count| *count == 0);
+            info!("User removed: {}", user_id);
+        }
+        removed
+    }
+
+    pub(crate) fn connection_count(&self, user_id: u64) -> usize {
+        self.connections.get(&user_id).map(|c| *c).unwrap_or(0)
+    }
+
+    pub fn connection_metrics(&self) -> ConnectionMetrics {
+        let counts: Vec<usize> = self
+            .connections
+            .iter()
+            .map(|c| *c)
+            .filter(|c| *c > 0)
+            .collect();
+        ConnectionMetrics {
+            users: self.users.len(),
+            connected_users: counts.len(),
+            connections: counts.iter().sum(),
+            sse_connections: self.transports.sse.load(Ordering::Relaxed),
+            ws_connections: self.transports.ws.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.connection_metrics())
+}
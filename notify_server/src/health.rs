@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+/// Status of the Postgres listener, every instance has its own.
+#[derive(Debug, Default)]
+pub(crate) struct ListenerStatus {
+    pub(crate) listening: AtomicBool,
+    pub(crate) notifications: AtomicU64,
+    pub(crate) duplicates: AtomicU64,
+    pub(crate) last_event_id: AtomicU64,
+    pub(crate) reconnects: AtomicU64,
+    pub(crate) batches: AtomicU64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    pub listening: bool,
+    /// notifications received, duplicates included
+    pub notifications: u64,
+    pub duplicates: u64,
+    pub last_event_id: u64,
+    /// attempts to reconnect the listener
+    pub reconnects: u64,
+    /// batches of notifications loaded together
+    pub batches: u64,
+}
+
+/// Health of the instance, the listener may be reconnecting.
+pub(crate) async fn health_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let listener = &state.listener;
+    Json(Health {
+        listening: listener.listening.load(Ordering::Relaxed),
+        notifications: listener.notifications.load(Ordering::Relaxed),
+        duplicates: listener.duplicates.load(Ordering::Relaxed),
+        last_event_id: listener.last_event_id.load(Ordering::Relaxed),
+        reconnects: listener.reconnects.load(Ordering::Relaxed),
+        batches: listener.batches.load(Ordering::Relaxed),
+    })
+}
+
+/// Ready to take connections only while listening to Postgres, events can't be delivered otherwise.
+pub(crate) async fn ready_handler(State(state): State<AppState>) -> impl IntoResponse {
+    if state.listener.listening.load(Ordering::Relaxed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -1,7 +1,13 @@
+mod batch;
+mod channel;
 mod config;
+mod connection;
 mod error;
+mod health;
 mod notif;
+mod presence;
 mod sse;
+mod ws;
 
 use axum::{
     http::Method,
@@ -10,20 +16,33 @@ use axum::{
     routing::get,
     Router,
 };
-use chat_core::{verify_token, DecodingKey, TokenVerify};
+use chat_core::{verify_token, DecodingKey, SessionCache, TokenClaims, TokenVerify};
+use connection::{metrics_handler, TransportCounters};
 use dashmap::DashMap;
 use error::AppError;
+use health::{health_handler, ready_handler, ListenerStatus};
+use notif::RecentEvents;
+use presence::setup_presence;
+use sqlx::PgPool;
 use sse::sse_handler;
-use std::{ops::Deref, sync::Arc};
-use tokio::sync::broadcast;
+use std::{
+    ops::Deref,
+    sync::{atomic::AtomicI64, Arc, Mutex},
+};
 use tower_http::cors::{self, CorsLayer};
+use tracing::debug;
+use ws::ws_handler;
 
+pub use channel::{UserChannel, UserEvent};
 pub use config::AppConfig;
-pub use notif::{setup_pg_listener, AppEvent};
+pub use connection::ConnectionMetrics;
+pub use health::Health;
+pub use notif::{setup_pg_listener, AppEvent, PresenceChanged, ReactionChanged, Typing};
+pub use ws::{ClientFrame, ServerFrame};
 
 const INDEX_HTML: &str = include_str!("../index.html");
 
-pub type UserMap = Arc<DashMap<u64, broadcast::Sender<Arc<AppEvent>>>>;
+pub type UserMap = Arc<DashMap<u64, UserChannel>>;
 
 #[derive(Clone)]
 pub struct AppState(Arc<AppStateInner>);
@@ -31,11 +50,25 @@ pub struct AppState(Arc<AppStateInner>);
 pub struct AppStateInner {
     pub config: AppConfig,
     pub users: UserMap,
+    /// number of active event connections of each user
+    connections: Arc<DashMap<u64, usize>>,
+    /// id of the instance in the database, its connections count for the presence of the users
+    instance_id: AtomicI64,
+    transports: TransportCounters,
+    /// ids of the recent events from the listener, for deduplication
+    recent_events: Mutex<RecentEvents>,
+    listener: ListenerStatus,
     dk: DecodingKey,
+    /// sessions of the recently verified tokens
+    sessions: SessionCache,
+    pool: PgPool,
+    /// pool to load the rows of the notifications
+    read_pool: PgPool,
 }
 
 pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
     let state = AppState::new(config);
+    setup_presence(state.clone()).await?;
     setup_pg_listener(state.clone()).await?;
 
     let cors = CorsLayer::new()
@@ -51,9 +84,13 @@ pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
 
     let router = Router::new()
         .route("/events", get(sse_handler))
+        .route("/ws", get(ws_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .layer(cors)
         .route("/", get(index_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/health", get(health_handler))
+        .route("/ready", get(ready_handler))
         .with_state(state.clone());
     Ok(router)
 }
@@ -64,9 +101,28 @@ async fn index_handler() -> impl IntoResponse {
 
 impl TokenVerify for AppState {
     type Error = AppError;
-    fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
+    fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
         Ok(self.dk.verify(token)?)
     }
+
+    /// Tokens of revoked sessions, or of users deactivated in the workspace of the token,
+    /// can't connect. Revocations on chat_server are seen after the cache ttl at the latest.
+    async fn verify_session(&self, claims: &TokenClaims) -> Result<chat_core::User, Self::Error> {
+        let user = self
+            .sessions
+            .load(claims, &self.pool)
+            .await
+            .map_err(anyhow::Error::from)?;
+        user.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Session {} is revoked, or user {} is not an active member of workspace {}",
+                claims.sid,
+                claims.uid,
+                claims.ws_id
+            )
+            .into()
+        })
+    }
 }
 
 impl Deref for AppState {
@@ -78,8 +134,45 @@ impl Deref for AppState {
 
 impl AppState {
     pub fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
+        let dk = DecodingKey::load_all(config.auth.pks()).expect("Failed to load public keys");
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        let connections = Arc::new(DashMap::new());
+        let pool = PgPool::connect_lazy(&config.server.db_url).expect("Invalid db url");
+        let read_pool = match &config.server.read_db_url {
+            Some(url) => PgPool::connect_lazy(url).expect("Invalid read db url"),
+            None => pool.clone(),
+        };
+        Self(Arc::new(AppStateInner {
+            config,
+            dk,
+            users,
+            connections,
+            instance_id: AtomicI64::new(0),
+            transports: TransportCounters::default(),
+            recent_events: Mutex::new(RecentEvents::default()),
+            listener: ListenerStatus::default(),
+            sessions: SessionCache::default(),
+            pool,
+            read_pool,
+        }))
+    }
+
+    /// Send the event to all users with an event channel.
+    pub(crate) fn broadcast(&self, event: Arc<AppEvent>) {
+        let event = UserEvent { id: 0, event };
+        for channel in self.users.iter() {
+            channel.send(event.clone());
+        }
+    }
+
+    /// Get the event channel of the user, created on the first connection of the user.
+    pub(crate) fn user_channel(
+        &self,
+        user_id: u64,
+    ) -> dashmap::mapref::one::RefMut<'_, u64, UserChannel> {
+        self.users.entry(user_id).or_insert_with(|| {
+            debug!("User added: {}", user_id);
+            UserChannel::new()
+        })
     }
 }
@@ -9,10 +9,10 @@ async fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry().with(layer).init();
 
     let config = AppConfig::load().expect("Failed to load config");
+    let addr = format!("0.0.0.0:{}", config.server.port);
     let app = get_router(config).await?;
 
-    let addr = "0.0.0.0:6687";
-    let listener = TcpListener::bind(addr).await?;
+    let listener = TcpListener::bind(&addr).await?;
     info!("Listening on {}", addr);
 
     axum::serve(listener, app).await?;
@@ -0,0 +1,166 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use chat_core::Presence;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// How often an instance tells the others it is alive.
+const INSTANCE_HEARTBEAT: Duration = Duration::from_secs(10);
+/// An instance not seen for that long is gone, its connections don't count any more.
+const INSTANCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Register the instance, so its connections count for the presence of the users.
+pub(crate) async fn setup_presence(state: AppState) -> anyhow::Result<()> {
+    state.register_instance().await?;
+    tokio::spawn(run_heartbeat(state));
+    Ok(())
+}
+
+async fn run_heartbeat(state: AppState) {
+    let mut interval = tokio::time::interval(INSTANCE_HEARTBEAT);
+    loop {
+        interval.tick().await;
+        if let Err(e) = state.heartbeat().await {
+            warn!("Failed to refresh notify instance: {}", e);
+        }
+    }
+}
+
+impl AppState {
+    /// Store the connections of the user on this instance and the presence matching the
+    /// connections on all instances: online while it has any, away while an instance keeps its
+    /// event channel for a reconnect, offline after. The database trigger notifies the
+    /// workspace if it changed.
+    ///
+    /// The writes of a user hold the lock of its presence row and only then read the
+    /// connections, so a write started before a reconnect can't land after it.
+    pub(crate) async fn sync_presence(&self, user_id: u64) {
+        if let Err(e) = self.try_sync_presence(user_id).await {
+            warn!("Failed to set presence of user {}: {}", user_id, e);
+        }
+    }
+
+    async fn try_sync_presence(&self, user_id: u64) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_presence(user_id, status)
+            VALUES($1, 'offline')
+            ON CONFLICT (user_id) DO NOTHING
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("SELECT status FROM user_presence WHERE user_id = $1 FOR UPDATE")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        let instance_id = self.instance_id.load(Ordering::Relaxed);
+        let count = self.connection_count(user_id);
+        if count > 0 || self.users.contains_key(&user_id) {
+            sqlx::query(
+                r#"
+                INSERT INTO notify_connections(instance_id, user_id, connections)
+                VALUES($1, $2, $3)
+                ON CONFLICT (user_id, instance_id) DO UPDATE SET connections = EXCLUDED.connections
+                "#,
+            )
+            .bind(instance_id)
+            .bind(user_id as i64)
+            .bind(count as i32)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query("DELETE FROM notify_connections WHERE instance_id = $1 AND user_id = $2")
+                .bind(instance_id)
+                .bind(user_id as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let (connections, instances): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COALESCE(SUM(c.connections), 0), COUNT(*)
+            FROM notify_connections c
+            JOIN notify_instances i ON i.id = c.instance_id
+            WHERE c.user_id = $1 AND i.seen_at > CURRENT_TIMESTAMP - make_interval(secs => $2)
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(INSTANCE_TIMEOUT.as_secs_f64())
+        .fetch_one(&mut *tx)
+        .await?;
+        let status = if connections > 0 {
+            Presence::Online
+        } else if instances > 0 {
+            Presence::Away
+        } else {
+            Presence::Offline
+        };
+        sqlx::query(
+            r#"
+            UPDATE user_presence
+            SET status = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND status <> $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(status)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+
+    async fn register_instance(&self) -> Result<(), sqlx::Error> {
+        let (id,): (i64,) =
+            sqlx::query_as("INSERT INTO notify_instances DEFAULT VALUES RETURNING id")
+                .fetch_one(&self.pool)
+                .await?;
+        self.instance_id.store(id, Ordering::Relaxed);
+        info!("Registered as notify instance {}", id);
+        Ok(())
+    }
+
+    /// Refresh this instance and drop the connections of the instances gone without notice.
+    async fn heartbeat(&self) -> Result<(), sqlx::Error> {
+        let seen =
+            sqlx::query("UPDATE notify_instances SET seen_at = CURRENT_TIMESTAMP WHERE id = $1")
+                .bind(self.instance_id.load(Ordering::Relaxed))
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        if seen == 0 {
+            // dropped by another instance while this one couldn't reach the database
+            warn!("Notify instance was dropped, registering again");
+            self.register_instance().await?;
+            let user_ids: Vec<u64> = self.users.iter().map(|u| *u.key()).collect();
+            for user_id in user_ids {
+                self.sync_presence(user_id).await;
+            }
+        }
+
+        // the connections of a gone instance are still visible to the select, the cascade
+        // deletes them at the end of the statement
+        let user_ids: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            WITH gone AS (
+                DELETE FROM notify_instances
+                WHERE seen_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+                RETURNING id
+            )
+            SELECT DISTINCT user_id FROM notify_connections
+            WHERE instance_id IN (SELECT id FROM gone)
+            "#,
+        )
+        .bind(INSTANCE_TIMEOUT.as_secs_f64())
+        .fetch_all(&self.pool)
+        .await?;
+        for (user_id,) in user_ids {
+            self.sync_presence(user_id as u64).await;
+        }
+        Ok(())
+    }
+}
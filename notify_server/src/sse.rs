@@ -1,47 +1,88 @@
-use std::{convert::Infallible, time::Duration};
+use std::{convert::Infallible, sync::Arc, time::Duration};
 
 use axum::{
     extract::State,
+    http::HeaderMap,
     response::{sse::Event, Sse},
     Extension,
 };
 use chat_core::User;
 use futures::Stream;
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
-use tracing::debug;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tracing::{debug, warn};
 
-use crate::{AppEvent, AppState};
+use crate::{
+    channel::Replay,
+    connection::{ConnectionGuard, Transport},
+    AppEvent, AppState, UserEvent,
+};
 
-const CHANNEL_CAPACITY: usize = 1024;
+const LAST_EVENT_ID: &str = "last-event-id";
 
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
     let user_id = user.id as u64;
-    let users = &state.users;
-
-    let rx = if let Some(tx) = users.get(&user_id) {
-        tx.subscribe()
-    } else {
-        debug!("User not found: {}", user_id);
-        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-        state.users.insert(user_id, tx);
-        debug!("User added: {}", user_id);
-        rx
+    let conn = ConnectionGuard::connect(state.clone(), user_id, Transport::Sse).await;
+
+    // browsers send the id of the last event they got when reconnecting
+    let last_event_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let (rx, missed) = {
+        let channel = state.user_channel(user_id);
+        match last_event_id {
+            Some(id) => match channel.resume(id) {
+                (rx, Replay::Events(events)) => (rx, events),
+                (rx, Replay::Resync(event)) => (rx, vec![event]),
+            },
+            None => (channel.subscribe(), vec![]),
+        }
     };
+    debug!("Replaying {} events to user {}", missed.len(), user_id);
+
+    let live = BroadcastStream::new(rx).map(move |v| match v {
+        Ok(v) => v,
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warn!("User {} lagged behind {} events", user_id, n);
+            // no id, so the client keeps the id of the last event it really got
+            UserEvent {
+                id: 0,
+                event: Arc::new(AppEvent::Resync),
+            }
+        }
+    });
 
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
+    // the guard lives as long as the stream, i.e. the connection
+    let stream = tokio_stream::iter(missed).chain(live).map(move |v| {
+        let _ = &conn;
+        let name = match v.event.as_ref() {
             AppEvent::NewChat(_) => "NewChat",
             AppEvent::AddToChat(_) => "AddToChat",
             AppEvent::RemoveFromChat(_) => "RemoveFromChat",
             AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::MessageUpdated(_) => "MessageUpdated",
+            AppEvent::MessageDeleted(_) => "MessageDeleted",
+            AppEvent::ReactionChanged(_) => "ReactionChanged",
+            AppEvent::ReadReceipt(_) => "ReadReceipt",
+            AppEvent::PresenceChanged(_) => "PresenceChanged",
+            AppEvent::Typing(_) => "Typing",
+            AppEvent::Resync => "Resync",
         };
-        let v = serde_json::to_string(&v).expect("Failed to serialize event");
-        debug!("Sending SSE event: {}: {:?}", name, v);
-        Ok(Event::default().data(v).event(name))
+        let data = serde_json::to_string(&v.event).expect("Failed to serialize event");
+        debug!("Sending SSE event: {}: {}: {:?}", v.id, name, data);
+        let event = Event::default().data(data).event(name);
+        if v.id > 0 {
+            Ok(event.id(v.id.to_string()))
+        } else {
+            Ok(event)
+        }
     });
 
     Sse::new(stream).keep_alive(
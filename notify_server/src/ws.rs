@@ -0,0 +1,230 @@
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use chat_core::{Chat, ReadReceipt, User};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::{
+    connection::{ConnectionGuard, Transport},
+    AppEvent, AppState,
+};
+
+/// Frames sent by the client, tagged by `type`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientFrame {
+    Ping,
+    /// Only receive the events of the subscribed chats, events of all chats if none is subscribed
+    #[serde(rename_all = "camelCase")]
+    Subscribe {
+        chat_ids: Vec<i64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unsubscribe {
+        chat_ids: Vec<i64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Typing {
+        chat_id: i64,
+    },
+    /// The messages of the chat are read up to the message
+    #[serde(rename_all = "camelCase")]
+    Ack {
+        chat_id: i64,
+        message_id: i64,
+    },
+}
+
+/// Replies to the client frames tagged by `type`, events are sent as they are, tagged by `event`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerFrame {
+    Pong,
+    #[serde(rename_all = "camelCase")]
+    Subscribed {
+        chat_ids: Vec<i64>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, user, state))
+}
+
+async fn handle_socket(socket: WebSocket, user: User, state: AppState) {
+    let user_id = user.id as u64;
+    let _conn = ConnectionGuard::connect(state.clone(), user_id, Transport::Ws).await;
+    let mut rx = state.user_channel(user_id).subscribe();
+    let (mut sender, mut receiver) = socket.split();
+    let mut chat_ids = HashSet::new();
+
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let event = match event {
+                    Ok(v) => v.event,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("User {} lagged behind {} events", user_id, n);
+                        Arc::new(AppEvent::Resync)
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !is_subscribed(&chat_ids, &event) {
+                    continue;
+                }
+                let v = serde_json::to_string(&*event).expect("Failed to serialize event");
+                debug!("Sending WebSocket event: {:?}", v);
+                if sender.send(Message::Text(v)).await.is_err() {
+                    break;
+                }
+            }
+            frame = receiver.next() => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    // ping and pong frames are answered by axum
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => continue,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                };
+                let reply = match serde_json::from_str(&text) {
+                    Ok(frame) => state.handle_client_frame(&user, frame, &mut chat_ids).await,
+                    Err(e) => Some(ServerFrame::Error {
+                        message: format!("invalid frame: {e}"),
+                    }),
+                };
+                if let Some(reply) = reply {
+                    let v = serde_json::to_string(&reply).expect("Failed to serialize frame");
+                    if sender.send(Message::Text(v)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    debug!("WebSocket of user {} closed", user_id);
+}
+
+fn is_subscribed(chat_ids: &HashSet<i64>, event: &AppEvent) -> bool {
+    match event.chat_id() {
+        Some(chat_id) if !chat_ids.is_empty() => chat_ids.contains(&chat_id),
+        _ => true,
+    }
+}
+
+impl AppState {
+    async fn handle_client_frame(
+        &self,
+        user: &User,
+        frame: ClientFrame,
+        chat_ids: &mut HashSet<i64>,
+    ) -> Option<ServerFrame> {
+        let ret = match frame {
+            ClientFrame::Ping => return Some(ServerFrame::Pong),
+            ClientFrame::Subscribe { chat_ids: ids } => {
+                chat_ids.extend(ids);
+                return Some(subscribed(chat_ids));
+            }
+            ClientFrame::Unsubscribe { chat_ids: ids } => {
+                for id in ids {
+                    chat_ids.remove(&id);
+                }
+                return Some(subscribed(chat_ids));
+            }
+            ClientFrame::Typing { chat_id } => self.notify_typing(chat_id, user).await,
+            ClientFrame::Ack {
+                chat_id,
+                message_id,
+            } => self.ack_message(chat_id, message_id, user).await,
+        };
+
+        match ret {
+            Ok(true) => None,
+            Ok(false) => Some(ServerFrame::Error {
+                message: "chat or message not found".to_string(),
+            }),
+            Err(e) => {
+                warn!("Failed to handle frame of user {}: {}", user.id, e);
+                Some(ServerFrame::Error {
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Same as typing through the chat server, but the membership is checked here: only active
+    /// members of the workspace of the token can type in its chats.
+    async fn notify_typing(&self, chat_id: i64, user: &User) -> Result<bool, sqlx::Error> {
+        let chat = sqlx::query(
+            r#"
+            SELECT 1
+            FROM chats c
+            JOIN workspace_members m
+                ON m.ws_id = c.ws_id AND m.user_id = $2 AND m.deactivated_at IS NULL
+            WHERE c.id = $1 AND c.ws_id = $3 AND $2 = ANY(c.members)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if chat.is_none() {
+            return Ok(false);
+        }
+
+        Chat::notify_typing(&self.pool, chat_id, user.id).await?;
+        Ok(true)
+    }
+
+    /// Move the read receipt forward to the message, the trigger notifies the chat members.
+    async fn ack_message(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        user: &User,
+    ) -> Result<bool, sqlx::Error> {
+        let msg = sqlx::query(
+            r#"
+            SELECT 1
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            JOIN workspace_members w
+                ON w.ws_id = c.ws_id AND w.user_id = $3 AND w.deactivated_at IS NULL
+            WHERE m.chat_id = $1 AND m.id = $2 AND c.ws_id = $4 AND $3 = ANY(c.members)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(message_id)
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if msg.is_none() {
+            return Ok(false);
+        }
+
+        ReadReceipt::upsert(&self.pool, chat_id, user.id, message_id).await?;
+        Ok(true)
+    }
+}
+
+fn subscribed(chat_ids: &HashSet<i64>) -> ServerFrame {
+    let mut chat_ids: Vec<_> = chat_ids.iter().copied().collect();
+    chat_ids.sort_unstable();
+    ServerFrame::Subscribed { chat_ids }
+}